@@ -0,0 +1,86 @@
+//! Compares the optimisation levels on a few programs. Run with
+//! `cargo bench --bench levels`.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use consize_interpreter::{
+    interpreter::Interpreter, pass::Pipeline, runner, stack_element::print_stack,
+};
+
+const PROGRAMS: [(&str, &str); 4] = [
+    ("loop", "0 [ [ 1 + ] call dup 20000 < ] loop"),
+    (
+        "recursion",
+        ": even? dup 0 == [ drop t ] [ 1 - odd? ] if ; \
+         : odd? dup 0 == [ drop f ] [ 1 - even? ] if ; \
+         20000 even?",
+    ),
+    ("map", "1 500 [a,b] [ dup * 1 + ] map 0 [ + ] reduce"),
+    (
+        "shuffle",
+        "1 2 3 20000 [ [ rot swap over drop ] dip 1 - dup 0 > ] loop drop",
+    ),
+];
+
+const LEVELS: u8 = 5;
+const RUNS: u32 = 5;
+
+/// The native stack the binary runs with, since level 4 nests deeply.
+const STACK_SIZE: usize = 1 << 30;
+
+/// The levels, and the JIT if it is built.
+fn pipelines() -> Vec<(String, Pipeline)> {
+    #[allow(unused_mut)]
+    let mut pipelines: Vec<_> = (0..=LEVELS)
+        .map(|level| (format!("level {level}"), Pipeline::level(level)))
+        .collect();
+    #[cfg(feature = "jit")]
+    pipelines.push(("jit".to_string(), Pipeline::parse("jit").unwrap()));
+    pipelines
+}
+
+/// The fastest of a few runs of `code` transformed by `pipeline`, and its
+/// result.
+fn measure(int: &Interpreter, code: &str, pipeline: &Pipeline) -> (Duration, String) {
+    let int = runner::optimise_with(int.clone(), pipeline);
+    let mut best = Duration::MAX;
+    let mut result = String::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let after = runner::run_with(int.clone(), code, pipeline);
+        best = best.min(start.elapsed());
+        result = print_stack(&after.datastack, false, false);
+    }
+    (best, result)
+}
+
+fn main() {
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| {
+            let int = runner::load_prelude(runner::primitives());
+            let pipelines = pipelines();
+            print!("{:<12}", "program");
+            for (name, _) in &pipelines {
+                print!("{name:>12}");
+            }
+            println!();
+
+            for (program, code) in PROGRAMS {
+                print!("{program:<12}");
+                let (_, expected) = measure(&int, code, &Pipeline::default());
+                for (name, pipeline) in &pipelines {
+                    let (time, result) = measure(&int, code, pipeline);
+                    assert_eq!(result, expected, "{program} at {name}");
+                    print!("{:>12}", format!("{:.1?}", time));
+                }
+                println!();
+            }
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    env,
+    fmt::Write as _,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    iter::Peekable,
+    ops::Deref,
+    path::PathBuf,
+    process,
+    rc::Rc,
+    time::SystemTime,
+};
+
+use crate::{
+    interpreter::Interpreter,
+    pass::Pipeline,
+    pragma::Pragmas,
+    preprocessor::{escape, Optimisation},
+    runner::{self, Prelude},
+    stack_element::{Funct, StackElement},
+};
+
+type Dictionary = BTreeMap<String, Rc<Funct>>;
+
+/// The first line of every cache file. Changing the format means changing
+/// the version, so old files are ignored.
+const FORMAT: &str = "consize-cache 1";
+
+/// The directory within the user's cache directory.
+const NAME: &str = "consize-interpreter";
+
+/// A directory of dictionaries as the prelude and the optimiser left them,
+/// so later runs can skip both. A file belongs to one prelude, pipeline and
+/// inline budget. Only dictionaries that are plain data are cached, which
+/// rules out the compiling pipelines and the bytecode.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$CONSIZE_CACHE_DIR`, or `consize-interpreter` in the user's cache
+    /// directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        env::var_os("CONSIZE_CACHE_DIR")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("XDG_CACHE_HOME").map(|dir| PathBuf::from(dir).join(NAME)))
+            .or_else(|| env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".cache").join(NAME)))
+    }
+
+    /// Loads `prelude` into `int`, which holds nothing but the primitives,
+    /// and optimises the dictionary for `pipeline`, like [`runner::load`]
+    /// followed by [`runner::optimise_with`]. The result of an earlier run
+    /// with the same prelude is read from the cache instead, and a new result
+    /// is written to it.
+    pub fn load_prelude(
+        &self,
+        int: Interpreter,
+        prelude: &Prelude,
+        pipeline: &Pipeline,
+    ) -> io::Result<Interpreter> {
+        let Some(path) = self.path(&int, prelude, pipeline) else {
+            return Ok(runner::optimise_with(runner::load(int, prelude)?, pipeline));
+        };
+        if let Ok(cached) = fs::read_to_string(&path) {
+            if let Ok(loaded) = restore(int.clone(), &cached) {
+                return Ok(loaded);
+            }
+        }
+
+        let primitives = int.dictionary.clone();
+        let loaded = runner::optimise_with(runner::load(int, prelude)?, pipeline);
+        if let Ok(saved) = save(&loaded, &primitives) {
+            // Written under a name of its own and renamed, so a concurrent
+            // run never reads half a file. A cache that cannot be written is
+            // no reason to fail.
+            let tmp = path.with_extension(format!("{}.tmp", process::id()));
+            let _ = fs::create_dir_all(&self.dir)
+                .and_then(|_| fs::write(&tmp, saved))
+                .and_then(|_| fs::rename(&tmp, &path));
+        }
+        Ok(loaded)
+    }
+
+    /// The file for `prelude`, `pipeline` and the inline budget of `int`, or
+    /// `None` if there is no prelude to read.
+    fn path(&self, int: &Interpreter, prelude: &Prelude, pipeline: &Pipeline) -> Option<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        FORMAT.hash(&mut hasher);
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        build().hash(&mut hasher);
+        match prelude {
+            Prelude::Embedded => (runner::PRELUDE, runner::BOOTIMAGE).hash(&mut hasher),
+            Prelude::File(file) => {
+                fs::read(file).ok()?.hash(&mut hasher);
+                fs::read(file.with_file_name("bootimage.txt"))
+                    .ok()
+                    .hash(&mut hasher);
+            }
+            Prelude::None => return None,
+        }
+        pipeline.to_string().hash(&mut hasher);
+        int.inline_budget.max_size.hash(&mut hasher);
+        let name = match pipeline.to_string().as_str() {
+            "" => "level0".to_string(),
+            passes => passes.replace(',', "-"),
+        };
+        Some(
+            self.dir
+                .join(format!("prelude-{:016x}-{name}.cache", hasher.finish())),
+        )
+    }
+}
+
+/// The size and modification time of the running executable, which change
+/// whenever it is rebuilt, even with the same version and different passes.
+fn build() -> Option<(u64, SystemTime)> {
+    let metadata = fs::metadata(env::current_exe().ok()?).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Writes the dictionary, pragmas and optimisation of `int` as text.
+/// `primitives` are the built-ins the dictionary started with, the only
+/// functions that can be restored by name.
+///
+/// Every line is a keyword followed by tokens. Words are written with a `'`
+/// in front, a primitive `dup` as `#dup`, the primitive as level 2 resolves
+/// it as `&dup` and an escaped element as `\` followed by the element.
+/// Stacks and mappings are enclosed in brackets and braces.
+pub fn save(int: &Interpreter, primitives: &Dictionary) -> Result<String, String> {
+    let encoder = Encoder { primitives };
+    let mut text = format!("{FORMAT}\n");
+    if let Some(optimisation) = &int.optimisation {
+        writeln!(text, "pipeline {}", optimisation.pipeline).unwrap();
+        encoder.dictionary(&mut text, "source", &optimisation.source)?;
+    }
+    for (word, pragmas) in int.pragmas.iter() {
+        let mut line = format!("pragma {}", encode_word(word));
+        if pragmas.inline {
+            line.push_str(" inline");
+        }
+        if pragmas.no_optimise {
+            line.push_str(" no-optimise");
+        }
+        if let Some(level) = pragmas.level {
+            write!(line, " level {level}").unwrap();
+        }
+        writeln!(text, "{line}").unwrap();
+    }
+    encoder.dictionary(&mut text, "word", &int.dictionary)?;
+    Ok(text)
+}
+
+/// Replaces the dictionary of `int` by the one [`save`] wrote to `text`.
+/// `int` has to hold the same primitives as the interpreter saved.
+pub fn restore(mut int: Interpreter, text: &str) -> Result<Interpreter, String> {
+    let mut lines = text.lines();
+    if lines.next() != Some(FORMAT) {
+        return Err("not a cache file of this version".to_string());
+    }
+
+    let decoder = Decoder {
+        primitives: &int.dictionary,
+    };
+    let mut pipeline = None;
+    let mut source = Dictionary::new();
+    let mut pragmas = BTreeMap::new();
+    let mut dictionary = Dictionary::new();
+    for line in lines {
+        let mut tokens = line.split(' ').peekable();
+        match tokens.next() {
+            Some("pipeline") => pipeline = Some(Pipeline::parse(tokens.next().unwrap_or(""))?),
+            Some("source") => decoder.entry(&mut tokens, &mut source)?,
+            Some("word") => decoder.entry(&mut tokens, &mut dictionary)?,
+            Some("pragma") => {
+                let word = decode_word(tokens.next().ok_or("missing word")?)?;
+                let stack: Vec<StackElement> = tokens
+                    .rev()
+                    .map(|t| StackElement::Word(t.to_string()))
+                    .collect();
+                let read = Pragmas::parse(&stack).map_err(|err| format!("{err} for {word}"))?;
+                pragmas.insert(word, read);
+            }
+            _ => return Err(format!("unexpected line `{line}`")),
+        }
+    }
+
+    int.dictionary = Rc::new(dictionary);
+    int.pragmas = Rc::new(pragmas);
+    int.optimisation = pipeline.map(|pipeline| {
+        Rc::new(Optimisation {
+            pipeline,
+            source: Rc::new(source),
+        })
+    });
+    Ok(int)
+}
+
+/// Words may hold any character, but tokens are separated by spaces and
+/// lines by newlines.
+fn encode_word(word: &str) -> String {
+    let mut encoded = String::new();
+    for c in word.chars() {
+        match c {
+            '%' => encoded.push_str("%25"),
+            c if c.is_whitespace() => {
+                for b in c.to_string().bytes() {
+                    write!(encoded, "%{b:02x}").unwrap();
+                }
+            }
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+fn decode_word(token: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut rest = token.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        match b {
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[..2]).map_err(|e| e.to_string())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|e| e.to_string())?);
+                rest = &tail[2..];
+            }
+            b => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+struct Encoder<'a> {
+    primitives: &'a Dictionary,
+}
+
+impl Encoder<'_> {
+    fn dictionary(
+        &self,
+        text: &mut String,
+        keyword: &str,
+        dictionary: &Dictionary,
+    ) -> Result<(), String> {
+        for (word, f) in dictionary {
+            let mut line = format!("{keyword} {}", encode_word(word));
+            match f.deref() {
+                Funct::BuiltIn(_) => write!(line, " #{}", encode_word(self.primitive(f)?)).unwrap(),
+                Funct::SelfDefined(body) => self.element(&mut line, body)?,
+                Funct::Compiled(..) => return Err(format!("{word} is compiled")),
+            }
+            writeln!(text, "{line}").unwrap();
+        }
+        Ok(())
+    }
+
+    /// The name of the primitive `f`.
+    fn primitive(&self, f: &Rc<Funct>) -> Result<&str, String> {
+        self.primitives
+            .iter()
+            .find(|(_, g)| Rc::ptr_eq(f, g))
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| "a function without source".to_string())
+    }
+
+    fn element(&self, line: &mut String, se: &StackElement) -> Result<(), String> {
+        match se {
+            StackElement::Word(w) => write!(line, " '{}", encode_word(w)).unwrap(),
+            StackElement::SubStack(ss) => {
+                line.push_str(" [");
+                for se in ss {
+                    self.element(line, se)?;
+                }
+                line.push_str(" ]");
+            }
+            StackElement::Map(m) => {
+                line.push_str(" {");
+                for (k, v) in m {
+                    self.element(line, k)?;
+                    self.element(line, v)?;
+                }
+                line.push_str(" }");
+            }
+            StackElement::Nil => line.push_str(" nil"),
+            StackElement::Fun(f) => match f.deref() {
+                Funct::BuiltIn(_) => write!(line, " #{}", encode_word(self.primitive(f)?)).unwrap(),
+                Funct::Compiled(src, bi) => match src.as_slice() {
+                    [StackElement::Word(name)]
+                        if matches!(
+                            self.primitives.get(name).map(|g| g.deref()),
+                            Some(Funct::BuiltIn(g)) if Rc::ptr_eq(g, bi)
+                        ) =>
+                    {
+                        write!(line, " &{}", encode_word(name)).unwrap()
+                    }
+                    [se, StackElement::Word(w)] if w == "\\" => {
+                        line.push_str(" \\");
+                        self.element(line, se)?;
+                    }
+                    _ => return Err(format!("compiled code `{se}`")),
+                },
+                Funct::SelfDefined(body) => {
+                    line.push_str(" @");
+                    self.element(line, body)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    primitives: &'a Dictionary,
+}
+
+impl Decoder<'_> {
+    fn entry<'t>(
+        &self,
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+        dictionary: &mut Dictionary,
+    ) -> Result<(), String> {
+        let word = decode_word(tokens.next().ok_or("missing word")?)?;
+        let f = match tokens.peek().copied() {
+            Some(t) if t.starts_with('#') => {
+                tokens.next();
+                self.primitive(t)?.clone()
+            }
+            _ => Rc::new(Funct::SelfDefined(self.element(tokens)?)),
+        };
+        if tokens.next().is_some() {
+            return Err(format!("trailing tokens after {word}"));
+        }
+        dictionary.insert(word, f);
+        Ok(())
+    }
+
+    fn primitive(&self, token: &str) -> Result<&Rc<Funct>, String> {
+        let name = decode_word(&token[1..])?;
+        self.primitives
+            .get(&name)
+            .filter(|f| matches!(&***f, Funct::BuiltIn(_)))
+            .ok_or_else(|| format!("unknown primitive {name}"))
+    }
+
+    fn element<'t>(
+        &self,
+        tokens: &mut Peekable<impl Iterator<Item = &'t str>>,
+    ) -> Result<StackElement, String> {
+        let token = tokens.next().ok_or("unexpected end of line")?;
+        Ok(match token {
+            "[" => {
+                let mut ss = Vec::new();
+                while tokens.peek() != Some(&"]") {
+                    ss.push(self.element(tokens)?);
+                }
+                tokens.next();
+                StackElement::SubStack(ss)
+            }
+            "{" => {
+                let mut m = Vec::new();
+                while tokens.peek() != Some(&"}") {
+                    m.push((self.element(tokens)?, self.element(tokens)?));
+                }
+                tokens.next();
+                StackElement::Map(m)
+            }
+            "nil" => StackElement::Nil,
+            "\\" => escape(self.element(tokens)?),
+            "@" => StackElement::Fun(Rc::new(Funct::SelfDefined(self.element(tokens)?))),
+            t if t.starts_with('\'') => StackElement::Word(decode_word(&t[1..])?),
+            t if t.starts_with('#') => StackElement::Fun(self.primitive(t)?.clone()),
+            t if t.starts_with('&') => match self.primitive(t)?.deref() {
+                Funct::BuiltIn(bi) => StackElement::Fun(Rc::new(Funct::Compiled(
+                    vec![StackElement::Word(decode_word(&t[1..])?)],
+                    bi.clone(),
+                ))),
+                _ => unreachable!(),
+            },
+            t => return Err(format!("unexpected token `{t}`")),
+        })
+    }
+}
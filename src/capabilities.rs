@@ -0,0 +1,123 @@
+use std::{
+    env, fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::error::{raise, ConsizeError};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileAccess {
+    None,
+    ReadOnly,
+    #[default]
+    ReadWrite,
+}
+
+/// The IO a Consize program is allowed to perform. The file primitives
+/// `slurp`, `spit` and `spit-on` (and with them `load` and `run`) as well as
+/// `read-line` check these before touching the outside world.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub files: FileAccess,
+    /// Confines file access to this directory. Relative paths are resolved
+    /// against it.
+    pub root: Option<PathBuf>,
+    pub stdin: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            files: FileAccess::ReadWrite,
+            root: None,
+            stdin: true,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Neither files nor stdin may be used.
+    pub fn no_io() -> Self {
+        Self {
+            files: FileAccess::None,
+            root: None,
+            stdin: false,
+        }
+    }
+
+    pub fn check_read(&self, primitive: &str, path: &str) -> PathBuf {
+        if self.files == FileAccess::None {
+            raise(ConsizeError::AccessDenied(format!(
+                "{primitive} may not read {path}"
+            )));
+        }
+        self.resolve(primitive, path)
+    }
+
+    pub fn check_write(&self, primitive: &str, path: &str) -> PathBuf {
+        if self.files != FileAccess::ReadWrite {
+            raise(ConsizeError::AccessDenied(format!(
+                "{primitive} may not write {path}"
+            )));
+        }
+        self.resolve(primitive, path)
+    }
+
+    pub fn check_stdin(&self, primitive: &str) {
+        if !self.stdin {
+            raise(ConsizeError::AccessDenied(format!(
+                "{primitive} may not read from stdin"
+            )));
+        }
+    }
+
+    fn resolve(&self, primitive: &str, path: &str) -> PathBuf {
+        let Some(root) = &self.root else {
+            return PathBuf::from(path);
+        };
+
+        let root = normalize(&env::current_dir().unwrap_or_default().join(root));
+        let resolved = normalize(&root.join(path));
+        if !resolved.starts_with(&root) || through_link(&root, &resolved) {
+            raise(ConsizeError::AccessDenied(format!(
+                "{primitive} may not access {path} outside of {}",
+                root.display()
+            )));
+        }
+
+        resolved
+    }
+}
+
+/// Removes `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+
+    normalized
+}
+
+/// Whether a component of `path` below `root` is a symbolic link. Links
+/// could point outside of the root, even those whose target does not exist
+/// yet, so none are followed.
+fn through_link(root: &Path, path: &Path) -> bool {
+    let mut current = root.to_path_buf();
+    for component in path.strip_prefix(root).unwrap_or(path).components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => return true,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+
+    false
+}
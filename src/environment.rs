@@ -0,0 +1,51 @@
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::io::Io;
+
+/// Name reported by `operating-system` in deterministic mode.
+const VIRTUAL_OS: &str = "consize";
+
+/// The facts about the outside world a Consize program can observe without
+/// doing IO: the current time, the operating system and, for primitives that
+/// need it, a random seed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Environment {
+    #[default]
+    Live,
+    /// Time is a virtual clock that starts at `seed` and advances by one
+    /// millisecond whenever it is read, so the same program always observes
+    /// the same values, at every optimisation level.
+    Deterministic { seed: u64 },
+}
+
+impl Environment {
+    /// The current time as `io` tells it. `clock` counts the reads of the
+    /// virtual clock so far.
+    pub fn current_time_millis(&self, clock: u64, io: &dyn Io) -> u128 {
+        match self {
+            Self::Live => io.current_time_millis(),
+            Self::Deterministic { seed } => *seed as u128 + clock as u128,
+        }
+    }
+
+    pub fn operating_system(&self) -> &'static str {
+        match self {
+            Self::Live => env::consts::OS,
+            Self::Deterministic { .. } => VIRTUAL_OS,
+        }
+    }
+
+    /// Seed for any source of randomness.
+    pub fn seed(&self) -> u64 {
+        match self {
+            Self::Live => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+            Self::Deterministic { seed } => *seed,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    fmt::Display,
+    panic::{self, AssertUnwindSafe},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsizeError {
+    DatastackOverflow(usize),
+    CallstackOverflow(usize),
+    ElementOverflow(usize),
+    NestingOverflow(usize),
+    AccessDenied(String),
+    Replay(String),
+    Record(String),
+}
+
+impl Display for ConsizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DatastackOverflow(max) => write!(f, "datastack exceeded {max} elements"),
+            Self::CallstackOverflow(max) => write!(f, "callstack exceeded {max} elements"),
+            Self::ElementOverflow(max) => write!(f, "stacks hold more than {max} elements"),
+            Self::NestingOverflow(max) => write!(f, "nesting depth exceeded {max} levels"),
+            Self::AccessDenied(reason) => write!(f, "access denied, {reason}"),
+            Self::Replay(reason) => write!(f, "replay diverged, {reason}"),
+            Self::Record(reason) => write!(f, "cannot record, {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConsizeError {}
+
+/// Aborts the running program with a Consize error. The error unwinds through
+/// all nested primitives and is turned into an `Err` by [`catch`].
+pub fn raise(err: ConsizeError) -> ! {
+    panic::panic_any(err)
+}
+
+/// Keeps the current panic hook from printing Consize errors, which
+/// [`catch`] reports as values. The hook is process-wide, so only binaries
+/// install it, once at startup.
+pub fn quiet_hook() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !info.payload().is::<ConsizeError>() {
+            hook(info);
+        }
+    }));
+}
+
+/// Runs `f` and returns the Consize error it raised, if any. Other panics are
+/// passed on unchanged.
+pub fn catch<T>(f: impl FnOnce() -> T) -> Result<T, ConsizeError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        match payload.downcast::<ConsizeError>() {
+            Ok(err) => *err,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    })
+}
@@ -1,16 +1,14 @@
-use std::{
-    collections::BTreeMap,
-    env,
-    fs::{self, OpenOptions},
-    io::{stdin, stdout, Error, Write},
-    ops::Deref,
-    rc::Rc,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{collections::BTreeMap, io::Error, ops::Deref, rc::Rc};
 
 use crate::{
-    preprocessor::call_fn_step_4,
-    stack_element::{map_to_dict, BuiltIn, Funct, StackElement},
+    capabilities::Capabilities,
+    environment::Environment,
+    io::{Io, OsIo},
+    limits::Limits,
+    pragma::Pragmas,
+    preprocessor::{reoptimise, InlineBudget, Optimisation},
+    stack_element::{map_to_dict, reify, BuiltIn, Funct, StackElement},
+    tiering::Tiering,
 };
 
 #[derive(Clone)]
@@ -18,6 +16,25 @@ pub struct Interpreter {
     pub datastack: Vec<StackElement>,
     pub callstack: Vec<StackElement>,
     pub dictionary: Rc<BTreeMap<String, Rc<Funct>>>,
+    pub limits: Limits,
+    pub capabilities: Rc<Capabilities>,
+    pub io: Rc<dyn Io>,
+    pub environment: Environment,
+    pub inline_budget: InlineBudget,
+    /// Set once the dictionary has been optimised.
+    pub optimisation: Option<Rc<Optimisation>>,
+    /// Set to promote hot words while the program runs.
+    pub tiering: Option<Rc<Tiering>>,
+    /// The pragmas of the definitions that have any.
+    pub pragmas: Rc<BTreeMap<String, Pragmas>>,
+    pub steps: usize,
+    /// The elements on both stacks when they were last counted plus those
+    /// created since, kept while [`Limits::max_elements`] is set.
+    pub elements: usize,
+    /// How often `current-time-millis` has read the virtual clock of
+    /// [`Environment::Deterministic`].
+    pub clock: u64,
+    pub nesting: usize,
 }
 
 impl Interpreter {
@@ -30,7 +47,97 @@ impl Interpreter {
             datastack,
             callstack,
             dictionary,
-        }
+            limits: Limits::default(),
+            capabilities: Rc::new(Capabilities::default()),
+            io: Rc::new(OsIo),
+            environment: Environment::default(),
+            inline_budget: InlineBudget::default(),
+            optimisation: None,
+            tiering: None,
+            pragmas: Rc::new(BTreeMap::new()),
+            steps: 0,
+            elements: 0,
+            clock: 0,
+            nesting: 0,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Rc::new(capabilities);
+        self
+    }
+
+    pub fn with_io(mut self, io: Rc<dyn Io>) -> Self {
+        self.io = io;
+        self
+    }
+
+    pub fn with_environment(mut self, environment: Environment) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    pub fn with_inline_budget(mut self, inline_budget: InlineBudget) -> Self {
+        self.inline_budget = inline_budget;
+        self
+    }
+
+    pub fn with_tiering(mut self, tiering: Tiering) -> Self {
+        self.tiering = Some(Rc::new(tiering));
+        self
+    }
+
+    /// Creates an interpreter for the given stacks that shares dictionary and
+    /// configuration with `self`.
+    pub fn fork(&self, datastack: Vec<StackElement>, callstack: Vec<StackElement>) -> Self {
+        Self {
+            datastack,
+            callstack,
+            dictionary: self.dictionary.clone(),
+            limits: self.limits,
+            capabilities: self.capabilities.clone(),
+            io: self.io.clone(),
+            environment: self.environment,
+            inline_budget: self.inline_budget,
+            optimisation: self.optimisation.clone(),
+            tiering: self.tiering.clone(),
+            pragmas: self.pragmas.clone(),
+            steps: self.steps,
+            elements: self.elements,
+            clock: self.clock,
+            nesting: self.nesting,
+        }
+    }
+
+    /// Counts one execution step and enforces the configured [`Limits`].
+    pub fn step(mut self) -> Self {
+        self.steps += 1;
+        let limits = self.limits;
+        limits.check(&mut self);
+        self
+    }
+
+    /// Accounts for the top `created` items of the datastack, which the
+    /// calling primitive has just made, against [`Limits::max_elements`].
+    fn created(&mut self, created: usize) {
+        let limits = self.limits;
+        limits.check_created(self, created);
+    }
+
+    /// Runs `f` one native call level deeper. Every primitive that executes
+    /// code by calling into Rust recursively goes through here, so runaway
+    /// recursion ends with a Consize error instead of a native stack overflow.
+    pub fn nested(mut self, f: &BuiltIn) -> Self {
+        self.nesting += 1;
+        self.limits.check_nesting(self.nesting);
+        let mut int = f(self);
+        int.nesting -= 1;
+        int
     }
 
     pub fn insert(dictionary: &mut BTreeMap<String, Rc<Funct>>, str: &str, f: BuiltIn) {
@@ -98,6 +205,7 @@ impl Interpreter {
         Self::insert(&mut dict, "load", Rc::new(Self::load));
         Self::insert(&mut dict, "run", Rc::new(Self::run));
         Self::insert(&mut dict, "start", Rc::new(Self::start));
+        Self::insert(&mut dict, "set-pragmas", Rc::new(Self::set_pragmas));
 
         dict
     }
@@ -106,6 +214,7 @@ impl Interpreter {
         let a = self.datastack.pop().unwrap();
         self.datastack.push(a.clone());
         self.datastack.push(a);
+        self.created(1);
 
         self
     }
@@ -139,6 +248,7 @@ impl Interpreter {
 
     pub fn emptystack(mut self) -> Self {
         self.datastack.push(StackElement::SubStack(Vec::new()));
+        self.created(1);
         self
     }
 
@@ -301,8 +411,9 @@ impl Interpreter {
             let key = self.datastack.pop().unwrap();
             let value = self.datastack.pop().unwrap();
 
-            if !map.iter().any(|(i, _)| *i == key) {
-                map.push((key, value));
+            match map.iter_mut().find(|(i, _)| *i == key) {
+                Some((_, v)) => *v = value,
+                None => map.push((key, value)),
             }
 
             self.datastack.push(StackElement::Map(map));
@@ -337,11 +448,13 @@ impl Interpreter {
                         StackElement::Fun(func) => match func.deref() {
                             Funct::BuiltIn(_) => j.to_owned(),
                             Funct::SelfDefined(st) => st.to_owned(),
+                            Funct::Compiled(src, _) => StackElement::SubStack(src.to_owned()),
                         },
                         _ => j.to_owned(),
                     },
                     None => default,
                 });
+            self.created(1);
 
             return self;
         }
@@ -397,6 +510,7 @@ impl Interpreter {
                     .rev()
                     .collect(),
             ));
+            self.created(1);
 
             return self;
         }
@@ -431,40 +545,45 @@ impl Interpreter {
     }
 
     pub fn print(mut self) -> Self {
-        print!("{}", self.datastack.pop().unwrap());
+        self.io.print(&self.datastack.pop().unwrap().to_string());
         //self.datastack.pop().unwrap();
 
         self
     }
 
     pub fn flush(self) -> Self {
-        stdout().flush().unwrap();
+        self.io.flush();
 
         self
     }
 
     pub fn read_line(mut self) -> Self {
-        let mut inp = "".to_string();
-        stdin().read_line(&mut inp).unwrap();
+        self.capabilities.check_stdin("read-line");
+        let inp = self.io.read_line();
 
         self.datastack.push(StackElement::Word(inp));
+        self.created(1);
         self
     }
 
     pub fn slurp(mut self) -> Self {
         if let StackElement::Word(src) = self.datastack.pop().unwrap() {
+            let src = self.capabilities.check_read("slurp", &src);
             self.datastack
-                .push(StackElement::Word(fs::read_to_string(src).unwrap()));
+                .push(StackElement::Word(self.io.read_file(&src).unwrap()));
+            self.created(1);
             return self;
         }
 
         panic!("need word to read from file")
     }
 
+    /// `spit ( file-wrd data-wrd -- )`
     pub fn spit(mut self) -> Self {
         if let StackElement::Word(data) = self.datastack.pop().unwrap() {
             if let StackElement::Word(file) = self.datastack.pop().unwrap() {
-                fs::write(file, data).unwrap();
+                let file = self.capabilities.check_write("spit", &file);
+                self.io.write_file(&file, &data).unwrap();
                 return self;
             }
         }
@@ -472,16 +591,12 @@ impl Interpreter {
         panic!("could not write file")
     }
 
+    /// `spit-on ( file-wrd data-wrd -- )`
     pub fn spit_on(mut self) -> Self {
         if let StackElement::Word(data) = self.datastack.pop().unwrap() {
             if let StackElement::Word(path) = self.datastack.pop().unwrap() {
-                let mut file = OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .open(path)
-                    .unwrap();
-
-                file.write_all(data.as_bytes()).unwrap();
+                let path = self.capabilities.check_write("spit-on", &path);
+                self.io.append_file(&path, &data).unwrap();
                 return self;
             }
         }
@@ -514,6 +629,7 @@ impl Interpreter {
                     .rev()
                     .collect(),
             ));
+            self.created(1);
             return self;
         }
 
@@ -526,19 +642,19 @@ impl Interpreter {
 
     pub fn ctm(mut self) -> Self {
         self.datastack.push(StackElement::Word(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
+            self.environment
+                .current_time_millis(self.clock, self.io.as_ref())
                 .to_string(),
         ));
+        self.clock += 1;
 
         self
     }
 
     pub fn os(mut self) -> Self {
-        self.datastack
-            .push(StackElement::Word(env::consts::OS.to_string()));
+        self.datastack.push(StackElement::Word(
+            self.environment.operating_system().to_string(),
+        ));
 
         self
     }
@@ -548,7 +664,7 @@ impl Interpreter {
             StackElement::SubStack(mut st) => self.callstack.append(&mut st),
             StackElement::Word(w) => self.callstack.push(StackElement::Word(w)),
             StackElement::Fun(f) => match f.deref() {
-                Funct::BuiltIn(bi) => return bi(self),
+                Funct::BuiltIn(bi) | Funct::Compiled(_, bi) => return bi(self),
                 Funct::SelfDefined(_) => unimplemented!(),
             },
             _ => unimplemented!(),
@@ -567,37 +683,19 @@ impl Interpreter {
 
         self.datastack = vec![
             StackElement::SubStack(self.datastack),
-            StackElement::SubStack(self.callstack),
+            StackElement::SubStack(reify(&self.callstack)),
         ];
+        self.callstack = new_callstack;
 
-        Self::new(self.datastack, new_callstack, self.dictionary)
-    }
-
-    pub fn call_cc_after_preprocess_step_4(mut self) -> Self {
-        let top = self.datastack.pop().unwrap();
-
-        self.datastack = vec![
-            StackElement::SubStack(self.datastack),
-            StackElement::SubStack(self.callstack),
-        ];
-
-        match top {
-            StackElement::SubStack(ss) => {
-                call_fn_step_4("asdfg".to_string(), &ss, &self.dictionary)(Self::new(
-                    self.datastack,
-                    Vec::new(),
-                    self.dictionary,
-                ))
-            }
-
-            _ => unimplemented!(),
-        }
+        self
     }
 
     pub fn r#continue(mut self) -> Self {
         if let StackElement::SubStack(new_callstack) = self.datastack.pop().unwrap() {
             if let StackElement::SubStack(new_datastack) = self.datastack.pop().unwrap() {
-                return Self::new(new_datastack, new_callstack, self.dictionary);
+                self.datastack = new_datastack;
+                self.callstack = new_callstack;
+                return self;
             }
         }
 
@@ -605,8 +703,16 @@ impl Interpreter {
     }
 
     pub fn get_dict(mut self) -> Self {
+        self.datastack.push(self.dictionary_map());
+        self.created(1);
+
+        self
+    }
+
+    /// The dictionary as the mapping `get-dict` pushes.
+    pub fn dictionary_map(&self) -> StackElement {
         let mut map = Vec::new();
-        self.dictionary.clone().iter().for_each(|(k, v)| {
+        self.dictionary.iter().for_each(|(k, v)| {
             let key = StackElement::Word(k.to_owned());
             let value = StackElement::Fun(v.to_owned());
             if !map.iter().map(|(i, _)| i).any(|se| key == *se) {
@@ -614,32 +720,53 @@ impl Interpreter {
             }
         });
 
-        self.datastack.push(StackElement::Map(map));
-
-        self
+        StackElement::Map(map)
     }
 
     pub fn set_dict(mut self) -> Self {
         if let StackElement::Map(dict) = self.datastack.pop().unwrap() {
-            let dict = map_to_dict(&dict).unwrap();
-            return Self::new(self.datastack, self.callstack, Rc::new(dict));
+            let mut dictionary = map_to_dict(&dict).unwrap();
+            // Pragmas belong to the definition they annotate.
+            let redefined = |(w, _): (&String, &Pragmas)| {
+                self.dictionary
+                    .get(w)
+                    .is_some_and(|old| dictionary.get(w).is_none_or(|f| !Rc::ptr_eq(old, f)))
+            };
+            if self.pragmas.iter().any(redefined) {
+                let pragmas = self.pragmas.iter().filter(|p| !redefined(*p));
+                self.pragmas = Rc::new(pragmas.map(|(w, p)| (w.clone(), *p)).collect());
+            }
+            if let Some(tiering) = &self.tiering {
+                dictionary = tiering.demote(&self.dictionary, dictionary);
+            }
+            return reoptimise(self, dictionary);
         }
 
         panic!("need map for set-dict")
     }
 
     pub fn stepcc(mut self) -> Self {
+        self = self.step();
         let e = self.callstack.pop().unwrap();
 
         match e {
             StackElement::SubStack(ss) => self.datastack.push(StackElement::SubStack(ss)),
             StackElement::Word(w) => {
-                match self.dictionary.clone().get(&w) {
+                let dictionary = self.dictionary.clone();
+                match dictionary.get(&w) {
                     Some(fun) => match fun.deref() {
-                        Funct::BuiltIn(fct) => {
+                        Funct::BuiltIn(fct) | Funct::Compiled(_, fct) => {
                             return fct(self);
                         }
                         Funct::SelfDefined(stack) => {
+                            if let Some(tiering) = self.tiering.clone() {
+                                self = tiering.count(self, &w, fun);
+                                // A promoted word runs in its new form.
+                                if !Rc::ptr_eq(&self.dictionary, &dictionary) {
+                                    self.callstack.push(StackElement::Word(w));
+                                    return self;
+                                }
+                            }
                             if let StackElement::SubStack(mut ss) = stack.to_owned() {
                                 self.callstack.append(&mut ss);
                             }
@@ -659,7 +786,7 @@ impl Interpreter {
             }
             StackElement::Nil => self.datastack.push(StackElement::Nil),
             StackElement::Fun(f) => match f.deref() {
-                Funct::BuiltIn(bi) => {
+                Funct::BuiltIn(bi) | Funct::Compiled(_, bi) => {
                     return bi(self);
                 }
                 Funct::SelfDefined(sd) => {
@@ -677,15 +804,15 @@ impl Interpreter {
         if let StackElement::Fun(fun) = self.datastack.pop().unwrap() {
             if let StackElement::SubStack(stack) = self.datastack.pop().unwrap() {
                 let int = match fun.deref() {
-                    Funct::BuiltIn(bi) => bi(Self::new(
-                        stack,
-                        self.callstack.clone(),
-                        self.dictionary.clone(),
-                    )),
-                    Funct::SelfDefined(_) => unimplemented!(),
+                    Funct::BuiltIn(bi) => self.fork(stack, self.callstack.clone()).nested(bi),
+                    _ => unimplemented!(),
                 };
                 self.datastack.push(StackElement::SubStack(int.datastack));
                 self.dictionary = int.dictionary;
+                self.optimisation = int.optimisation;
+                self.pragmas = int.pragmas;
+                self.steps = int.steps;
+                self.clock = int.clock;
             }
         }
 
@@ -710,7 +837,10 @@ impl Interpreter {
 
                 let f = move |interpreter: Interpreter| {
                     let qt = qt.to_owned();
-                    runcc(Self::new(interpreter.datastack, qt, interpreter.dictionary))
+                    runcc(Self {
+                        callstack: qt,
+                        ..interpreter
+                    })
                 };
 
                 self.datastack
@@ -721,19 +851,6 @@ impl Interpreter {
         self
     }
 
-    pub fn func_after_preprocess_step_4(mut self) -> Self {
-        if let StackElement::Map(_dict) = self.datastack.pop().unwrap() {
-            if let StackElement::SubStack(qt) = self.datastack.pop().unwrap() {
-                let f = call_fn_step_4("asdfgh".to_string(), &qt, &self.dictionary);
-
-                self.datastack
-                    .push(StackElement::Fun(Rc::new(Funct::BuiltIn(f))));
-            }
-        }
-
-        self
-    }
-
     pub fn integer(mut self) -> Self {
         let e = self.datastack.pop().unwrap();
         self.datastack.push(match e {
@@ -828,33 +945,14 @@ impl Interpreter {
 
     pub fn comment(mut self) -> Self {
         let next = self.callstack.pop().unwrap();
+        let mut source = reify(&[next]);
+        let next = source.pop().unwrap();
+        self.callstack.append(&mut source);
         self.datastack.push(next);
 
         self
     }
 
-    pub fn comment_after_preprocess(self) -> Self {
-        panic!("das machen wir hier nicht mehr")
-    }
-
-    pub fn call_after_preproccess_step_4(mut self) -> Self {
-        match self.datastack.pop().unwrap() {
-            StackElement::SubStack(st) => {
-                call_fn_step_4("asdfgh".to_string(), st.as_slice(), &self.dictionary)(self)
-            }
-            StackElement::Fun(f) => match f.deref() {
-                Funct::BuiltIn(bi) => bi(self),
-                _ => unimplemented!(),
-            },
-            StackElement::Word(f) => call_fn_step_4(
-                "qwert".to_string(),
-                &[StackElement::Word(f)],
-                &self.dictionary,
-            )(self),
-            x => panic!("{:?}", x),
-        }
-    }
-
     pub fn load(self) -> Self {
         self.slurp().uncomment().tokenize()
     }
@@ -863,10 +961,6 @@ impl Interpreter {
         self.load().call()
     }
 
-    pub fn run_after_preprocess_step_4(self) -> Self {
-        self.load().call_after_preproccess_step_4()
-    }
-
     pub fn start(self) -> Self {
         self.apply()
             .swap()
@@ -877,4 +971,35 @@ impl Interpreter {
             .uncomment()
             .slurp()
     }
+
+    /// `set-pragmas ( wrd pragmas -- t/f )` annotates the definition of
+    /// `wrd` with the stack `pragmas` and optimises it again, or pushes `f`
+    /// and leaves everything as it is if they are not valid.
+    pub fn set_pragmas(mut self) -> Self {
+        let pragmas = self.datastack.pop().unwrap();
+        let word = self.datastack.pop().unwrap();
+        if let (StackElement::Word(w), StackElement::SubStack(pragmas)) = (word, pragmas) {
+            let Ok(pragmas) = Pragmas::parse(&pragmas) else {
+                self.datastack.push(StackElement::Word("f".to_string()));
+                return self;
+            };
+            let mut all = (*self.pragmas).clone();
+            match pragmas == Pragmas::default() {
+                true => all.remove(&w),
+                false => all.insert(w.clone(), pragmas),
+            };
+            self.pragmas = Rc::new(all);
+            let source = self.optimisation.as_ref().and_then(|o| o.source.get(&w));
+            if let Some(Funct::SelfDefined(body)) = source.map(|f| f.deref()) {
+                // A fresh copy of the definition counts as changed.
+                let mut dictionary = (*self.dictionary).clone();
+                dictionary.insert(w, Rc::new(Funct::SelfDefined(body.clone())));
+                self = reoptimise(self, dictionary);
+            }
+            self.datastack.push(StackElement::Word("t".to_string()));
+            return self;
+        }
+
+        panic!("set-pragmas needs a word and a stack")
+    }
 }
@@ -0,0 +1,217 @@
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, VecDeque},
+    fs::{self, OpenOptions},
+    io::{self, stdin, stdout, ErrorKind, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Everything the primitives `print`, `flush`, `read-line`, `slurp`, `spit`,
+/// `spit-on` and `current-time-millis` need from the outside world.
+pub trait Io {
+    fn print(&self, text: &str);
+    fn flush(&self);
+    fn read_line(&self) -> String;
+    fn read_file(&self, path: &Path) -> io::Result<String>;
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()>;
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()>;
+    /// Milliseconds since the Unix epoch.
+    fn current_time_millis(&self) -> u128;
+}
+
+fn system_time_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+/// Stdout, stdin and the real file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsIo;
+
+impl Io for OsIo {
+    fn print(&self, text: &str) {
+        print!("{text}");
+    }
+
+    fn flush(&self) {
+        stdout().flush().unwrap();
+    }
+
+    fn read_line(&self) -> String {
+        let mut inp = "".to_string();
+        stdin().read_line(&mut inp).unwrap();
+        inp
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        fs::write(path, data)
+    }
+
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        OpenOptions::new()
+            .append(true)
+            .open(path)?
+            .write_all(data.as_bytes())
+    }
+
+    fn current_time_millis(&self) -> u128 {
+        system_time_millis()
+    }
+}
+
+/// Captures all output and serves input lines, files and the time from
+/// memory, so programs can be run without touching the terminal, the disk or
+/// the clock.
+#[derive(Debug, Default)]
+pub struct MemoryIo {
+    output: RefCell<String>,
+    input: RefCell<VecDeque<String>>,
+    files: RefCell<BTreeMap<PathBuf, String>>,
+    time: Cell<u128>,
+}
+
+impl MemoryIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a line for `read-line`. The line separator is added if missing.
+    pub fn with_input(self, line: &str) -> Self {
+        let line = match line.ends_with('\n') {
+            true => line.to_string(),
+            false => format!("{line}\n"),
+        };
+        self.input.borrow_mut().push_back(line);
+        self
+    }
+
+    pub fn with_file(self, path: impl Into<PathBuf>, content: &str) -> Self {
+        self.files
+            .borrow_mut()
+            .insert(path.into(), content.to_string());
+        self
+    }
+
+    /// Sets the time `current-time-millis` reports, 0 unless set.
+    pub fn set_time(&self, millis: u128) {
+        self.time.set(millis);
+    }
+
+    /// Everything printed so far.
+    pub fn output(&self) -> String {
+        self.output.borrow().clone()
+    }
+
+    pub fn file(&self, path: impl AsRef<Path>) -> Option<String> {
+        self.files.borrow().get(path.as_ref()).cloned()
+    }
+}
+
+impl Io for MemoryIo {
+    fn print(&self, text: &str) {
+        self.output.borrow_mut().push_str(text);
+    }
+
+    fn flush(&self) {}
+
+    /// Behaves like stdin at its end once all queued lines are consumed.
+    fn read_line(&self) -> String {
+        self.input.borrow_mut().pop_front().unwrap_or_default()
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.file(path)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, path.display().to_string()))
+    }
+
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.files
+            .borrow_mut()
+            .insert(path.to_path_buf(), data.to_string());
+        Ok(())
+    }
+
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        match self.files.borrow_mut().get_mut(path) {
+            Some(content) => {
+                content.push_str(data);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                ErrorKind::NotFound,
+                path.display().to_string(),
+            )),
+        }
+    }
+
+    fn current_time_millis(&self) -> u128 {
+        self.time.get()
+    }
+}
+
+/// Serves some files from memory and leaves everything else, including
+/// writes to these files, to another `Io`.
+pub struct OverlayIo {
+    inner: Rc<dyn Io>,
+    files: BTreeMap<PathBuf, Cow<'static, str>>,
+}
+
+impl OverlayIo {
+    pub fn new(inner: Rc<dyn Io>) -> Self {
+        Self {
+            inner,
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_file(
+        mut self,
+        path: impl Into<PathBuf>,
+        content: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.files.insert(path.into(), content.into());
+        self
+    }
+}
+
+impl Io for OverlayIo {
+    fn print(&self, text: &str) {
+        self.inner.print(text);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+
+    fn read_line(&self) -> String {
+        self.inner.read_line()
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        match self.files.get(path) {
+            Some(content) => Ok(content.to_string()),
+            None => self.inner.read_file(path),
+        }
+    }
+
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.inner.write_file(path, data)
+    }
+
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.inner.append_file(path, data)
+    }
+
+    fn current_time_millis(&self) -> u128 {
+        self.inner.current_time_millis()
+    }
+}
@@ -0,0 +1,796 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    mem,
+    ops::Deref,
+    ptr,
+    rc::{Rc, Weak},
+};
+
+use cranelift_codegen::{
+    ir::{
+        condcodes::IntCC, types, AbiParam, Block, InstBuilder, MemFlags, Type, UserFuncName, Value,
+    },
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::{
+    interpreter::Interpreter,
+    preprocessor::PURE,
+    stack_element::{Funct, StackElement},
+    vm::{Code, Op, Vm},
+};
+
+/// How often bytecode is entered before it is compiled to machine code, and
+/// how often a kernel may fail before it is given up.
+const HOT_RUNS: u32 = 64;
+
+/// The most instructions a kernel may contain after inlining the words and
+/// quotations it calls.
+const MAX_OPS: usize = 1024;
+
+/// Compiles hot bytecode of the [`Vm`] to machine code. Only code made of
+/// literals, pure primitives, branches and calls of such code is compiled,
+/// and only integers and truth values are computed natively. Everything else
+/// keeps running in the VM.
+pub struct Jit {
+    module: RefCell<Option<JITModule>>,
+    int_type: Type,
+}
+
+impl Jit {
+    /// A compiler for the host, or `None` if Cranelift does not support it.
+    pub fn new() -> Option<Rc<Self>> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").ok()?;
+        flags.set("is_pic", "false").ok()?;
+        flags.set("opt_level", "speed").ok()?;
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(settings::Flags::new(flags))
+            .ok()?;
+        let int_type = isa.pointer_type();
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        Some(Rc::new(Self {
+            module: RefCell::new(Some(module)),
+            int_type,
+        }))
+    }
+
+    /// Runs `code` as machine code once it is hot and compiles. Returns
+    /// whether it ran, otherwise the VM has to run it.
+    pub(crate) fn run(self: &Rc<Self>, vm: &Vm, code: &Code, int: &mut Interpreter) -> bool {
+        let native = &code.native;
+        let mut kernel = native.kernel.borrow_mut();
+        let current = match &*kernel {
+            Some((dictionary, _)) => ptr::eq(dictionary.as_ptr(), Rc::as_ptr(&int.dictionary)),
+            None => false,
+        };
+        if !current {
+            native.runs.set(native.runs.get() + 1);
+            if native.runs.get() < HOT_RUNS {
+                return false;
+            }
+            native.runs.set(0);
+            // Kernels only move elements they do not compute, so code taking
+            // stacks is left to the VM for as long as the dictionary stays.
+            let compiled = self
+                .compile(vm, code, int)
+                .filter(|kernel| kernel.accepts(int));
+            *kernel = Some((Rc::downgrade(&int.dictionary), compiled));
+        }
+
+        let Some((_, Some(compiled))) = &*kernel else {
+            return false;
+        };
+        if compiled.run(int) {
+            return true;
+        }
+        if compiled.given_up() {
+            if let Some((_, compiled)) = &mut *kernel {
+                *compiled = None;
+            }
+        }
+        false
+    }
+
+    fn compile(self: &Rc<Self>, vm: &Vm, code: &Code, int: &Interpreter) -> Option<Kernel> {
+        let mut module = self.module.borrow_mut();
+        let module = module.as_mut()?;
+
+        let mut signature = module.make_signature();
+        signature
+            .params
+            .push(AbiParam::new(module.target_config().pointer_type()));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let mut context = module.make_context();
+        context.func.signature = signature.clone();
+        let mut function_context = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut function_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let values = builder.block_params(entry)[0];
+        let bail = builder.create_block();
+        // Tail calls of `code` itself jump back here.
+        let header = builder.create_block();
+        let steps = builder.append_block_param(header, types::I64);
+        let zero = builder.ins().iconst(types::I64, 0);
+        builder.ins().jump(header, &[zero]);
+        builder.switch_to_block(header);
+
+        let mut emitter = Emitter {
+            builder,
+            int_type: self.int_type,
+            values,
+            bail,
+            root: code,
+            header,
+            overwritten: Vec::new(),
+            loop_inputs: 0,
+            primitives: primitives(&int.dictionary),
+            word: &|w| vm.word(w, int),
+            quotation: &|qt| vm.quotation(qt, int),
+            integers: Vec::new(),
+            calls: Vec::new(),
+            ops: 0,
+        };
+        let mut state = State {
+            items: Vec::new(),
+            inputs: 0,
+            steps,
+            pending: 0,
+        };
+        if emitter.emit(code, &mut state, true)? == Flow::Repeats {
+            return None;
+        }
+        state.reach(emitter.loop_inputs);
+
+        // Inputs replaced by a tail call have to be read back from their slot.
+        let mut outputs = Vec::new();
+        let mut results = Vec::new();
+        for (slot, item) in state.items.clone().into_iter().rev().enumerate() {
+            let output = match item {
+                Item::Input(i) if emitter.overwritten.contains(&i) => {
+                    results.push((slot, emitter.value(item, Kind::Int)?));
+                    Output::Value(Kind::Int)
+                }
+                Item::Value(value, kind) => {
+                    results.push((slot, value));
+                    Output::Value(kind)
+                }
+                Item::Input(i) => Output::Input(i),
+                Item::Literal(se) => Output::Literal(se),
+            };
+            outputs.push(output);
+        }
+        emitter.store(results);
+        let integers = emitter.integers;
+        let mut builder = emitter.builder;
+        let steps = state.flush(&mut builder);
+        builder.ins().return_(&[steps]);
+        builder.switch_to_block(bail);
+        let failed = builder.ins().iconst(types::I64, -1);
+        builder.ins().return_(&[failed]);
+        builder.seal_all_blocks();
+        builder.finalize();
+
+        let id = module.declare_anonymous_function(&signature).ok()?;
+        context.func.name = UserFuncName::user(0, id.as_u32());
+        module.define_function(id, &mut context).ok()?;
+        module.clear_context(&mut context);
+        module.finalize_definitions().ok()?;
+        let function = module.get_finalized_function(id);
+        Some(Kernel {
+            // SAFETY: the function was compiled with this signature, and the
+            // kernel keeps the module that owns its memory alive.
+            function: unsafe { mem::transmute::<*const u8, KernelFn>(function) },
+            inputs: state.inputs,
+            moved: outputs
+                .iter()
+                .filter_map(|output| match output {
+                    Output::Input(i) => Some(*i),
+                    _ => None,
+                })
+                .collect(),
+            integers,
+            outputs,
+            runs: Cell::new(0),
+            failures: Cell::new(0),
+            _jit: self.clone(),
+        })
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.get_mut().take() {
+            // SAFETY: every kernel holds the `Jit`, so none is left.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// The state of the compiler for a piece of bytecode.
+#[derive(Default)]
+pub(crate) struct Native {
+    runs: Cell<u32>,
+    /// The kernel together with the dictionary it was compiled for, or `None`
+    /// if the code cannot be compiled.
+    kernel: RefCell<Option<(Weak<Dictionary>, Option<Kernel>)>>,
+}
+
+type Dictionary = BTreeMap<String, Rc<Funct>>;
+
+/// Reads integers from and writes results to the given slots, the top of the
+/// datastack first. Returns the number of steps taken, or -1 if the VM has to
+/// run the code instead.
+type KernelFn = unsafe extern "C" fn(*mut i64) -> i64;
+
+struct Kernel {
+    function: KernelFn,
+    /// How many elements the kernel takes from the datastack.
+    inputs: usize,
+    /// The inputs that have to be integers.
+    integers: Vec<usize>,
+    /// The inputs left on the datastack as they are.
+    moved: Vec<usize>,
+    /// What the kernel leaves on the datastack, top first.
+    outputs: Vec<Output>,
+    runs: Cell<u32>,
+    failures: Cell<u32>,
+    _jit: Rc<Jit>,
+}
+
+enum Output {
+    Value(Kind),
+    Input(usize),
+    Literal(StackElement),
+}
+
+thread_local! {
+    static KERNEL_RUNS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// How often machine code compiled by the JIT ran on this thread.
+pub fn kernel_runs() -> usize {
+    KERNEL_RUNS.get()
+}
+
+impl Kernel {
+    fn run(&self, int: &mut Interpreter) -> bool {
+        let ran = self.try_run(int);
+        if ran {
+            KERNEL_RUNS.set(KERNEL_RUNS.get() + 1);
+        }
+        let count = match ran {
+            true => &self.runs,
+            false => &self.failures,
+        };
+        count.set(count.get().saturating_add(1));
+        ran
+    }
+
+    /// Whether the kernel fails at least as often as it runs.
+    fn given_up(&self) -> bool {
+        self.failures.get() >= HOT_RUNS && self.failures.get() >= self.runs.get()
+    }
+
+    /// Whether the datastack holds integers where the kernel computes and no
+    /// stacks where it would have to copy them.
+    fn accepts(&self, int: &Interpreter) -> bool {
+        let len = int.datastack.len();
+        len >= self.inputs
+            && self
+                .integers
+                .iter()
+                .all(|&i| match &int.datastack[len - 1 - i] {
+                    StackElement::Word(w) => integer(w).is_some(),
+                    _ => false,
+                })
+            && self
+                .moved
+                .iter()
+                .all(|&i| !is_stack(&int.datastack[len - 1 - i]))
+    }
+
+    fn try_run(&self, int: &mut Interpreter) -> bool {
+        // The VM checks the limits at every step and call, the kernel does
+        // not.
+        let limits = int.limits;
+        if limits.max_datastack.is_some()
+            || limits.max_callstack.is_some()
+            || limits.max_elements.is_some()
+        {
+            return false;
+        }
+        let Some(base) = int.datastack.len().checked_sub(self.inputs) else {
+            return false;
+        };
+        let elements = &int.datastack[base..];
+        if self
+            .moved
+            .iter()
+            .any(|&i| is_stack(&elements[self.inputs - 1 - i]))
+        {
+            return false;
+        }
+        let mut values = vec![0i64; self.inputs.max(self.outputs.len())];
+        for &i in &self.integers {
+            match &int.datastack[int.datastack.len() - 1 - i] {
+                StackElement::Word(w) => match integer(w) {
+                    Some(n) => values[i] = n,
+                    None => return false,
+                },
+                _ => return false,
+            }
+        }
+
+        // SAFETY: `values` holds every slot the kernel reads or writes.
+        let steps = unsafe { (self.function)(values.as_mut_ptr()) };
+        if steps < 0 {
+            return false;
+        }
+        let taken = int.datastack.split_off(base);
+        for (slot, output) in self.outputs.iter().enumerate().rev() {
+            let se = match output {
+                Output::Value(Kind::Int) => StackElement::Word(values[slot].to_string()),
+                Output::Value(Kind::Bool) => {
+                    StackElement::Word(if values[slot] != 0 { "t" } else { "f" }.to_string())
+                }
+                Output::Input(i) => taken[self.inputs - 1 - i].clone(),
+                Output::Literal(se) => se.clone(),
+            };
+            int.datastack.push(se);
+        }
+        int.steps += steps as usize;
+        true
+    }
+}
+
+fn is_stack(se: &StackElement) -> bool {
+    matches!(se, StackElement::SubStack(_) | StackElement::Map(_))
+}
+
+/// `w` as a number if printing the number gives `w` back, so the kernel
+/// returns the words the primitives would.
+fn integer(w: &str) -> Option<i64> {
+    let digits = w.strip_prefix('-').unwrap_or(w);
+    let canonical = digits.bytes().all(|c| c.is_ascii_digit())
+        && (digits == "0" || !digits.starts_with('0'))
+        && w != "-0";
+    match canonical {
+        true => w.parse::<isize>().ok().map(|n| n as i64),
+        false => None,
+    }
+}
+
+/// The pure primitives by the address of their function.
+fn primitives(dictionary: &Dictionary) -> Vec<(*const u8, &'static str)> {
+    PURE.iter()
+        .filter_map(|name| match dictionary.get(*name).map(|f| f.deref()) {
+            Some(Funct::BuiltIn(f)) => Some((Rc::as_ptr(f) as *const u8, *name)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Int,
+    Bool,
+}
+
+/// An element on the datastack while compiling.
+#[derive(Clone, PartialEq)]
+enum Item {
+    /// Computed by the kernel.
+    Value(Value, Kind),
+    /// The `i`th element of the datastack when the kernel started, top
+    /// first, which the kernel only moves around.
+    Input(usize),
+    Literal(StackElement),
+}
+
+impl Item {
+    /// The kind of value the item can be computed as.
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Item::Value(_, kind) => Some(*kind),
+            Item::Input(_) => Some(Kind::Int),
+            Item::Literal(StackElement::Word(w)) if w == "t" || w == "f" => Some(Kind::Bool),
+            Item::Literal(StackElement::Word(w)) => integer(w).map(|_| Kind::Int),
+            Item::Literal(_) => None,
+        }
+    }
+}
+
+/// The datastack while compiling: the elements pushed so far, and how many
+/// elements below them have been taken.
+#[derive(Clone)]
+struct State {
+    items: Vec<Item>,
+    inputs: usize,
+    steps: Value,
+    /// Steps not yet added to `steps`.
+    pending: i64,
+}
+
+impl State {
+    fn flush(&mut self, builder: &mut FunctionBuilder) -> Value {
+        if self.pending != 0 {
+            self.steps = builder.ins().iadd_imm(self.steps, self.pending);
+            self.pending = 0;
+        }
+        self.steps
+    }
+
+    /// Takes untouched elements until `inputs` elements are taken.
+    fn reach(&mut self, inputs: usize) {
+        let below = (self.inputs..inputs).rev().map(Item::Input);
+        self.items.splice(0..0, below);
+        self.inputs = self.inputs.max(inputs);
+    }
+
+    fn pop(&mut self) -> Item {
+        self.items.pop().unwrap_or_else(|| {
+            self.inputs += 1;
+            Item::Input(self.inputs - 1)
+        })
+    }
+}
+
+struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    int_type: Type,
+    values: Value,
+    bail: Block,
+    /// The code being compiled and the block its body starts in.
+    root: *const Code,
+    header: Block,
+    /// The slots tail calls write to.
+    overwritten: Vec<usize>,
+    /// How many inputs tail calls pass on at most.
+    loop_inputs: usize,
+    primitives: Vec<(*const u8, &'static str)>,
+    word: &'a dyn Fn(&str) -> Option<Rc<Code>>,
+    quotation: &'a dyn Fn(Vec<StackElement>) -> Rc<Code>,
+    /// The inputs read as integers.
+    integers: Vec<usize>,
+    /// The words being inlined, to reject recursion.
+    calls: Vec<String>,
+    ops: usize,
+}
+
+/// How emitted code ends.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Continues,
+    /// Jumps back to the start of the kernel.
+    Repeats,
+}
+
+impl Emitter<'_> {
+    /// Emits `code`, counting the steps the VM would take. `tail` tells
+    /// whether nothing follows the code in the kernel.
+    fn emit(&mut self, code: &Code, state: &mut State, tail: bool) -> Option<Flow> {
+        for (i, op) in code.ops.iter().enumerate() {
+            let tail = tail && i + 1 == code.ops.len();
+            self.ops += 1;
+            if self.ops > MAX_OPS {
+                return None;
+            }
+            state.pending += 1;
+            match op {
+                Op::Push(se) => state.items.push(Item::Literal(se.clone())),
+                Op::Prim(f) => {
+                    let address = Rc::as_ptr(f) as *const u8;
+                    let (_, name) = self.primitives.iter().find(|(p, _)| ptr::eq(*p, address))?;
+                    self.primitive(name, state)?;
+                }
+                Op::Call(word) => {
+                    let callee = (self.word)(word)?;
+                    if tail && ptr::eq(Rc::as_ptr(&callee), self.root) {
+                        self.repeat(state)?;
+                        return Some(Flow::Repeats);
+                    }
+                    if self.calls.contains(word) {
+                        return None;
+                    }
+                    self.calls.push(word.clone());
+                    let flow = self.emit(&callee, state, tail)?;
+                    self.calls.pop();
+                    if flow == Flow::Repeats {
+                        return Some(flow);
+                    }
+                }
+                Op::CallQuotation => match state.pop() {
+                    Item::Literal(StackElement::SubStack(qt)) => {
+                        if self.emit(&(self.quotation)(qt), state, tail)? == Flow::Repeats {
+                            return Some(Flow::Repeats);
+                        }
+                    }
+                    _ => return None,
+                },
+                Op::Branch(then, otherwise) => {
+                    let flow = match state.pop() {
+                        Item::Literal(StackElement::Word(w)) if w == "f" => {
+                            self.emit(otherwise, state, tail)?
+                        }
+                        Item::Literal(_) | Item::Value(_, Kind::Int) => {
+                            self.emit(then, state, tail)?
+                        }
+                        Item::Value(condition, Kind::Bool) => {
+                            self.branch(condition, then, otherwise, state, tail)?
+                        }
+                        Item::Input(_) => return None,
+                    };
+                    if flow == Flow::Repeats {
+                        return Some(flow);
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(Flow::Continues)
+    }
+
+    /// Emits a tail call of the kernel itself, which has to leave as many
+    /// elements as it takes. They are written to the slots of the inputs.
+    fn repeat(&mut self, state: &mut State) -> Option<()> {
+        if state.items.len() != state.inputs {
+            return None;
+        }
+        self.loop_inputs = self.loop_inputs.max(state.inputs);
+        let mut results = Vec::new();
+        for (slot, item) in state.items.clone().into_iter().rev().enumerate() {
+            if item != Item::Input(slot) {
+                results.push((slot, self.value(item, Kind::Int)?));
+                if !self.overwritten.contains(&slot) {
+                    self.overwritten.push(slot);
+                }
+            }
+        }
+        self.store(results);
+        let steps = state.flush(&mut self.builder);
+        self.builder.ins().jump(self.header, &[steps]);
+        Some(())
+    }
+
+    /// Writes values to their slots, after all of them have been computed.
+    fn store(&mut self, results: Vec<(usize, Value)>) {
+        for (slot, value) in results {
+            let value = self.widen(value);
+            self.builder
+                .ins()
+                .store(MemFlags::trusted(), value, self.values, 8 * slot as i32);
+        }
+    }
+
+    fn branch(
+        &mut self,
+        condition: Value,
+        then: &Code,
+        otherwise: &Code,
+        state: &mut State,
+        tail: bool,
+    ) -> Option<Flow> {
+        state.flush(&mut self.builder);
+        let then_block = self.builder.create_block();
+        let else_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, then_block, &[], else_block, &[]);
+
+        self.builder.switch_to_block(then_block);
+        let mut then_state = state.clone();
+        let then_flow = self.emit(then, &mut then_state, tail)?;
+        let then_end = self.builder.current_block()?;
+
+        self.builder.switch_to_block(else_block);
+        let mut else_state = state.clone();
+        let else_flow = self.emit(otherwise, &mut else_state, tail)?;
+
+        // A branch that repeats the kernel does not continue here.
+        match (then_flow, else_flow) {
+            (Flow::Repeats, Flow::Repeats) => return Some(Flow::Repeats),
+            (Flow::Repeats, Flow::Continues) => {
+                *state = else_state;
+                return Some(Flow::Continues);
+            }
+            (Flow::Continues, Flow::Repeats) => {
+                self.builder.switch_to_block(then_end);
+                *state = then_state;
+                return Some(Flow::Continues);
+            }
+            (Flow::Continues, Flow::Continues) => (),
+        }
+
+        let inputs = then_state.inputs.max(else_state.inputs);
+        then_state.reach(inputs);
+        else_state.reach(inputs);
+        if then_state.items.len() != else_state.items.len() {
+            return None;
+        }
+        // Items that differ between the branches become parameters of the
+        // block both continue in.
+        let mut kinds = Vec::new();
+        for (a, b) in then_state.items.iter().zip(&else_state.items) {
+            kinds.push(match a == b {
+                true => None,
+                false => Some(a.kind().filter(|kind| b.kind() == Some(*kind))?),
+            });
+        }
+
+        let merged = self.builder.create_block();
+        let else_args = self.arguments(&mut else_state, &kinds)?;
+        self.builder.ins().jump(merged, &else_args);
+        self.builder.switch_to_block(then_end);
+        let then_args = self.arguments(&mut then_state, &kinds)?;
+        self.builder.ins().jump(merged, &then_args);
+
+        self.builder.switch_to_block(merged);
+        let mut items = Vec::new();
+        for (item, kind) in then_state.items.iter().zip(&kinds) {
+            items.push(match kind {
+                Some(kind) => {
+                    let ty = self.type_of(*kind);
+                    Item::Value(self.builder.append_block_param(merged, ty), *kind)
+                }
+                None => item.clone(),
+            });
+        }
+        let steps = self.builder.append_block_param(merged, types::I64);
+        *state = State {
+            items,
+            inputs: then_state.inputs,
+            steps,
+            pending: 0,
+        };
+        Some(Flow::Continues)
+    }
+
+    /// What a branch passes on: the items that differ as values of the given
+    /// kinds, followed by the steps.
+    fn arguments(&mut self, state: &mut State, kinds: &[Option<Kind>]) -> Option<Vec<Value>> {
+        let mut args = Vec::new();
+        for (item, kind) in state.items.clone().into_iter().zip(kinds) {
+            if let Some(kind) = kind {
+                args.push(self.value(item, *kind)?);
+            }
+        }
+        args.push(state.flush(&mut self.builder));
+        Some(args)
+    }
+
+    fn type_of(&self, kind: Kind) -> Type {
+        match kind {
+            Kind::Int => self.int_type,
+            Kind::Bool => types::I8,
+        }
+    }
+
+    /// `item` as a value computed by the kernel.
+    fn value(&mut self, item: Item, kind: Kind) -> Option<Value> {
+        match (item, kind) {
+            (Item::Value(value, k), _) if k == kind => Some(value),
+            (Item::Literal(StackElement::Word(w)), Kind::Int) => {
+                Some(self.builder.ins().iconst(self.int_type, integer(&w)?))
+            }
+            (Item::Literal(StackElement::Word(w)), Kind::Bool) if w == "t" || w == "f" => {
+                Some(self.builder.ins().iconst(types::I8, (w == "t") as i64))
+            }
+            (Item::Input(i), Kind::Int) => {
+                if !self.integers.contains(&i) {
+                    self.integers.push(i);
+                }
+                let offset = i32::try_from(8 * i).ok()?;
+                let value =
+                    self.builder
+                        .ins()
+                        .load(types::I64, MemFlags::trusted(), self.values, offset);
+                Some(match self.int_type == types::I64 {
+                    true => value,
+                    false => self.builder.ins().ireduce(self.int_type, value),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// `value` extended to the width of a slot.
+    fn widen(&mut self, value: Value) -> Value {
+        match self.builder.func.dfg.value_type(value) == types::I64 {
+            true => value,
+            false => self.builder.ins().sextend(types::I64, value),
+        }
+    }
+
+    fn primitive(&mut self, name: &str, state: &mut State) -> Option<()> {
+        match name {
+            "dup" => {
+                let item = state.pop();
+                state.items.extend([item.clone(), item]);
+            }
+            "drop" => {
+                state.pop();
+            }
+            "swap" => {
+                let x = state.pop();
+                let y = state.pop();
+                state.items.extend([x, y]);
+            }
+            "rot" => {
+                let z = state.pop();
+                let y = state.pop();
+                let x = state.pop();
+                state.items.extend([y, z, x]);
+            }
+            _ => {
+                let x = state.pop();
+                let y = state.pop();
+                let x = self.value(x, Kind::Int)?;
+                let y = self.value(y, Kind::Int)?;
+                let item = match name {
+                    "+" => Item::Value(self.checked(|b| b.ins().sadd_overflow(y, x)), Kind::Int),
+                    "-" => Item::Value(self.checked(|b| b.ins().ssub_overflow(y, x)), Kind::Int),
+                    "*" => Item::Value(self.checked(|b| b.ins().smul_overflow(y, x)), Kind::Int),
+                    "div" => {
+                        self.check_division(y, x);
+                        Item::Value(self.builder.ins().sdiv(y, x), Kind::Int)
+                    }
+                    "mod" => {
+                        self.check_division(y, x);
+                        Item::Value(self.builder.ins().srem(y, x), Kind::Int)
+                    }
+                    _ => {
+                        let cc = match name {
+                            "<" => IntCC::SignedLessThan,
+                            ">" => IntCC::SignedGreaterThan,
+                            "==" => IntCC::Equal,
+                            "<=" => IntCC::SignedLessThanOrEqual,
+                            _ => IntCC::SignedGreaterThanOrEqual,
+                        };
+                        Item::Value(self.builder.ins().icmp(cc, y, x), Kind::Bool)
+                    }
+                };
+                state.items.push(item);
+            }
+        }
+        Some(())
+    }
+
+    /// Leaves the kernel if the operation overflows, where the primitive
+    /// would panic or wrap around.
+    fn checked(&mut self, op: impl FnOnce(&mut FunctionBuilder) -> (Value, Value)) -> Value {
+        let (result, overflow) = op(&mut self.builder);
+        self.bail_if(overflow);
+        result
+    }
+
+    fn check_division(&mut self, y: Value, x: Value) {
+        let min = match self.int_type == types::I64 {
+            true => i64::MIN,
+            false => i32::MIN as i64,
+        };
+        let b = &mut self.builder;
+        let zero = b.ins().icmp_imm(IntCC::Equal, x, 0);
+        let minus_one = b.ins().icmp_imm(IntCC::Equal, x, -1);
+        let smallest = b.ins().icmp_imm(IntCC::Equal, y, min);
+        let overflow = b.ins().band(minus_one, smallest);
+        let failing = b.ins().bor(zero, overflow);
+        self.bail_if(failing);
+    }
+
+    fn bail_if(&mut self, condition: Value) {
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, self.bail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+}
@@ -0,0 +1,19 @@
+pub mod cache;
+pub mod capabilities;
+pub mod environment;
+pub mod error;
+pub mod interpreter;
+pub mod io;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod limits;
+pub mod pass;
+pub mod pragma;
+pub mod preprocessor;
+pub mod replay;
+pub mod runner;
+pub mod stack_element;
+pub mod tiering;
+pub mod transpile;
+pub mod verify;
+pub mod vm;
@@ -0,0 +1,69 @@
+use crate::{
+    error::{raise, ConsizeError},
+    interpreter::Interpreter,
+    stack_element::count_elements,
+};
+
+/// Counting every element is linear in the size of both stacks, so the total
+/// is only recounted every this many steps. In between, primitives that
+/// create elements add them to [`Interpreter::elements`], see
+/// [`Limits::check_created`].
+pub const ELEMENT_CHECK_INTERVAL: usize = 1024;
+
+/// Upper bounds for the resources a Consize program may use. `None` means
+/// unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_datastack: Option<usize>,
+    pub max_callstack: Option<usize>,
+    pub max_elements: Option<usize>,
+    pub max_nesting: Option<usize>,
+}
+
+impl Limits {
+    pub fn check(&self, int: &mut Interpreter) {
+        if let Some(max) = self.max_datastack {
+            if int.datastack.len() > max {
+                raise(ConsizeError::DatastackOverflow(max));
+            }
+        }
+        if let Some(max) = self.max_callstack {
+            if int.callstack.len() > max {
+                raise(ConsizeError::CallstackOverflow(max));
+            }
+        }
+        if self.max_elements.is_some() && int.steps.is_multiple_of(ELEMENT_CHECK_INTERVAL) {
+            self.recount(int);
+        }
+    }
+
+    /// Adds the elements of the top `created` items of the datastack, which a
+    /// primitive has just made or copied, to the running total. Primitives
+    /// that only move elements, like `concat` or `push`, leave the total as
+    /// it is. Once the total exceeds the limit, both stacks are counted again.
+    pub fn check_created(&self, int: &mut Interpreter, created: usize) {
+        if let Some(max) = self.max_elements {
+            int.elements += count_elements(&int.datastack[int.datastack.len() - created..]);
+            if int.elements > max {
+                self.recount(int);
+            }
+        }
+    }
+
+    fn recount(&self, int: &mut Interpreter) {
+        if let Some(max) = self.max_elements {
+            int.elements = count_elements(&int.datastack) + count_elements(&int.callstack);
+            if int.elements > max {
+                raise(ConsizeError::ElementOverflow(max));
+            }
+        }
+    }
+
+    pub fn check_nesting(&self, nesting: usize) {
+        if let Some(max) = self.max_nesting {
+            if nesting > max {
+                raise(ConsizeError::NestingOverflow(max));
+            }
+        }
+    }
+}
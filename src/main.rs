@@ -1,143 +1,379 @@
-use clap::{arg, Command};
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
 use colored::Colorize;
+use consize_interpreter::{
+    cache::Cache,
+    capabilities::{Capabilities, FileAccess},
+    environment::Environment,
+    error::{catch, quiet_hook},
+    interpreter::Interpreter,
+    io::{Io, OsIo},
+    limits::Limits,
+    pass::{Pipeline, MAX_LEVEL},
+    preprocessor::{transformed, InlineBudget},
+    replay::{RecordingIo, ReplayIo},
+    runner::{self, Prelude},
+    stack_element::{print_ir, print_stack, StackElement},
+    tiering::Tiering,
+    transpile::transpile,
+    verify::verify_with,
+};
 use core::panic;
 use cpu_time::ProcessTime;
-use interpreter::Interpreter;
-use preprocessor::{call_fn_step_1, call_fn_step_2, call_fn_step_3, call_fn_step_4, optimise_dict};
-use stack_element::{print_stack, Funct, StackElement};
-use std::{ops::Deref, rc::Rc};
+use std::{
+    fs,
+    io::{self, read_to_string, stdin},
+    path::{Path, PathBuf},
+    process::exit,
+    rc::Rc,
+    thread,
+};
 
-pub mod interpreter;
-pub mod preprocessor;
-pub mod stack_element;
+/// Primitives like `apply` execute Consize as nested Rust calls, so the
+/// interpreter runs on a thread with a stack large enough for the default
+/// `--max-nesting`.
+const STACK_SIZE: usize = 1 << 30;
 
 fn main() {
     let cli = load_program_data().get_matches();
-    let end;
+    quiet_hook();
 
-    let int = Interpreter {
-        datastack: vec![StackElement::Word("\\ prelude-plain.txt run".to_string())],
-        callstack: Vec::new(),
-        dictionary: Rc::new(Interpreter::init_dictionary()),
-    };
+    let result = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || catch(|| execute(&cli)))
+        .unwrap()
+        .join()
+        .unwrap_or_else(|_| exit(101));
 
-    let code: String = cli
-        .get_one::<String>("code")
-        .expect("Code has to be provided")
-        .clone();
-    let mut int2 = call(int, 0);
-
-    let int3 = match cli
-        .get_one::<String>("level")
-        .unwrap_or(&"0".to_string())
-        .parse::<u8>()
-        .expect(format!("{} level needs to be numeric", "Error:".bold().red()).as_str())
-    {
-        0 => {
-            int2.datastack = vec![StackElement::Word(code)];
-            let start = ProcessTime::now();
-            let ret = call(int2, 0);
-            end = Some(start.elapsed());
-
-            ret
-        }
-        i if i > 0_u8 && i < 4_u8 => {
-            let mut int = optimise_dict(int2, i);
-            int.datastack = vec![StackElement::Word(code)];
+    if let Err(err) = result {
+        eprintln!("{} {}", "Error:".bold().red(), err);
+        exit(1);
+    }
+}
 
-            let start = ProcessTime::now();
+fn execute(cli: &ArgMatches) {
+    let int = runner::primitives()
+        .with_environment(environment(cli))
+        .with_inline_budget(InlineBudget {
+            max_size: *cli.get_one::<usize>("inline-budget").unwrap(),
+        });
 
-            let ret = call(int, i);
-            end = Some(start.elapsed());
+    let (code, args) = program(cli);
 
-            ret
-        }
-        4 => {
-            let mut new_dict = int2.dictionary.deref().clone();
-            new_dict.insert(
-                "call".to_string(),
-                Rc::new(Funct::BuiltIn(Rc::new(
-                    Interpreter::call_after_preproccess_step_4,
-                ))),
-            );
-            new_dict.insert(
-                "\\".to_string(),
-                Rc::new(Funct::BuiltIn(Rc::new(
-                    Interpreter::comment_after_preprocess,
-                ))),
-            );
-            new_dict.insert(
-                "run".to_string(),
-                Rc::new(Funct::BuiltIn(Rc::new(
-                    Interpreter::run_after_preprocess_step_4,
-                ))),
-            );
-            new_dict.insert(
-                "call/cc".to_string(),
-                Rc::new(Funct::BuiltIn(Rc::new(
-                    Interpreter::call_cc_after_preprocess_step_4,
-                ))),
-            );
-            new_dict.insert(
-                "func".to_string(),
-                Rc::new(Funct::BuiltIn(Rc::new(
-                    Interpreter::func_after_preprocess_step_4,
-                ))),
-            );
-            int2.dictionary = Rc::new(new_dict);
-
-            let mut int = optimise_dict(int2, 4);
-            int.datastack = vec![StackElement::Word(code)];
-            let start = ProcessTime::now();
-            let ret = call(int, 4);
-            end = Some(start.elapsed());
-
-            ret
-        }
-        _ => panic!("level has to be between 0 and 4"),
+    let level = cli.get_one::<u8>("level").copied().unwrap_or(0);
+    let (pipeline, name) = match cli.get_one::<String>("passes") {
+        Some(passes) => (
+            Pipeline::parse(passes).expect("--passes is validated by clap"),
+            format!("passes {passes}"),
+        ),
+        None => (Pipeline::level(level), format!("level {level}")),
+    };
+    // Everything but a plain run optimises the dictionary itself.
+    let plain = !cli.get_flag("verify")
+        && ["emit", "transpile", "adaptive", "dump-bootimage"]
+            .iter()
+            .all(|id| !cli.contains_id(id));
+    let preloads: Vec<&PathBuf> = cli.get_many("preload").into_iter().flatten().collect();
+    // Files preloaded on top of the prelude are optimised along with it.
+    let preloaded = match plain && preloads.is_empty() {
+        true => &pipeline,
+        false => &Pipeline::default(),
+    };
+    let prelude = prelude(cli);
+    // The prelude and the preloaded files are trusted, so the sandbox and
+    // the limits only apply afterwards.
+    let mut int2 = match (cli.get_one::<String>("bootimage"), cache(cli)) {
+        (Some(bootimage), _) => runner::optimise_with(runner::boot(int, bootimage), preloaded),
+        (None, Some(cache)) => or_exit(cache.load_prelude(int, &prelude, preloaded)),
+        (None, None) => runner::optimise_with(or_exit(runner::load(int, &prelude)), preloaded),
     };
+    for file in &preloads {
+        int2 = or_exit(runner::load_file(int2, file));
+    }
+    if plain && !preloads.is_empty() {
+        int2 = runner::optimise_with(int2, &pipeline);
+    }
+    let mut int2 = int2
+        .with_capabilities(capabilities(cli))
+        .with_limits(limits(cli))
+        .with_io(io(cli));
+    if let Some(file) = cli.get_one::<String>("dump-bootimage") {
+        runner::dump_bootimage(int2, file);
+        return println!("Wrote {file}");
+    }
+    if let Some(level) = cli.get_one::<String>("adaptive") {
+        int2 = int2.with_tiering(Tiering::new(
+            level.parse().unwrap(),
+            *cli.get_one::<u32>("hot-calls").unwrap(),
+        ));
+    }
+    if let Some(emit) = cli.get_one::<String>("emit") {
+        let level = emit.trim_start_matches("level").parse().unwrap();
+        let word = cli.get_one::<String>("emit-word").map(String::as_str);
+        return execute_emit(int2, &code, &Pipeline::level(level), word);
+    }
+    if let Some(dir) = cli.get_one::<PathBuf>("transpile") {
+        let runtime = cli.get_one::<PathBuf>("runtime").map(|dir| {
+            // The crate is built elsewhere, so a relative path would not do.
+            std::path::absolute(dir).unwrap_or_else(|_| dir.clone())
+        });
+        return execute_transpile(int2, &code, dir, runtime.as_deref());
+    }
+    let int2 = runner::with_args(int2, &args);
+    if cli.get_flag("verify") {
+        return execute_verified(int2, &code, &pipeline, &name);
+    }
+
+    let start = ProcessTime::now();
+    let int3 = runner::run_with(int2, &code, &pipeline);
+    let end = start.elapsed();
 
     println!(
         "{} {} Took {:?}",
         "Consize returns:".yellow().bold(),
         print_stack(&int3.datastack, false, false),
-        end.unwrap()
+        end
     )
 }
 
-fn call(int: Interpreter, level: u8) -> Interpreter {
-    let mut int1 = int.uncomment().tokenize();
-    let new_datastack = match level {
-        0 => int1.datastack,
-        1 => call_fn_step_1("asdfghj".to_string(), &int1.datastack, &int1.dictionary),
-        2 => call_fn_step_2("asdfghj".to_string(), &int1.datastack, &int1.dictionary),
-        3 => call_fn_step_3("asdfghj".to_string(), &int1.datastack, &int1.dictionary),
-        4 => match int1.datastack.pop().unwrap() {
-            StackElement::SubStack(ss) => vec![StackElement::Fun(Rc::new(Funct::BuiltIn(
-                call_fn_step_4("asdfghj".to_string(), &ss, &int1.dictionary),
-            )))],
-            _ => panic!("passiert nicht"),
+fn execute_verified(int: Interpreter, code: &str, pipeline: &Pipeline, name: &str) {
+    let io = int.io.clone();
+    let outcome = verify_with(int, code, pipeline).unwrap_or_else(|divergence| {
+        eprintln!(
+            "{} {name} diverges from level 0: {divergence}",
+            "Error:".bold().red()
+        );
+        exit(1)
+    });
+
+    io.print(&outcome.output);
+    match outcome.result {
+        Ok(datastack) => println!(
+            "{} {} Same as level 0",
+            "Consize returns:".yellow().bold(),
+            print_stack(&datastack, false, false)
+        ),
+        Err(err) => {
+            eprintln!("{} {err}", "Error:".bold().red());
+            exit(1)
+        }
+    }
+}
+
+/// Prints the definitions and the program as `pipeline` transforms them.
+fn execute_emit(int: Interpreter, code: &str, pipeline: &Pipeline, word: Option<&str>) {
+    let mut int = runner::optimise_with(int, pipeline);
+    let definitions = transformed(&int, word);
+    if let (Some(word), true) = (word, definitions.is_empty()) {
+        eprintln!(
+            "{} {word} is not a self-defined word",
+            "Error:".bold().red()
+        );
+        exit(1)
+    }
+    for (name, body) in &definitions {
+        println!(": {name} {} ;", print_ir(body, &int.dictionary));
+    }
+
+    if word.is_none() {
+        int.datastack = vec![StackElement::Word(code.to_string())];
+        let mut int = runner::transform(int, pipeline);
+        if let Some(StackElement::SubStack(program)) = int.datastack.pop() {
+            println!("% program\n{}", print_ir(&program, &int.dictionary));
+        }
+    }
+}
+
+/// Writes a crate that runs `code` at level 2 to `dir`, built with the
+/// library in `runtime` if given.
+fn execute_transpile(int: Interpreter, code: &str, dir: &Path, runtime: Option<&Path>) {
+    let int = runner::optimise(int, 2);
+    let name = dir.file_name().map_or("consize-program".to_string(), |n| {
+        n.to_string_lossy().into_owned()
+    });
+    let result = transpile(&int, code, &name, runtime)
+        .and_then(|krate| krate.write(dir).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        eprintln!(
+            "{} cannot transpile to {}: {err}",
+            "Error:".bold().red(),
+            dir.display()
+        );
+        exit(1)
+    }
+    println!(
+        "Wrote {}, build it with cargo build --release --manifest-path {}",
+        dir.display(),
+        dir.join("Cargo.toml").display()
+    );
+}
+
+/// The code to run and the arguments for it. Without `-e`, the first
+/// argument names the file to run, or stdin as `-`.
+fn program(cli: &ArgMatches) -> (String, Vec<String>) {
+    let mut args: Vec<String> = ["program", "args"]
+        .iter()
+        .flat_map(|id| cli.get_many::<String>(id).into_iter().flatten().cloned())
+        .collect();
+    if let Some(expressions) = cli.get_many::<String>("expression") {
+        return (expressions.cloned().collect::<Vec<_>>().join("\n"), args);
+    }
+    if args.is_empty() {
+        return (String::new(), args);
+    }
+    let file = args.remove(0);
+    let source = match file.as_str() {
+        "-" => read_to_string(stdin()),
+        path => fs::read_to_string(path),
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("{} cannot read {file}: {err}", "Error:".bold().red());
+        exit(1)
+    });
+    (skip_shebang(source), args)
+}
+
+/// Blanks a first line starting with `#!`, so scripts can be made
+/// executable. Consize would read it as words.
+fn skip_shebang(source: String) -> String {
+    match source.starts_with("#!") {
+        true => source
+            .find('\n')
+            .map_or(String::new(), |i| source[i..].to_string()),
+        false => source,
+    }
+}
+
+/// The loaded interpreter, or the end of the run if a file could not be read.
+fn or_exit(loaded: io::Result<Interpreter>) -> Interpreter {
+    loaded.unwrap_or_else(|err| {
+        eprintln!("{} {err}", "Error:".bold().red());
+        exit(1)
+    })
+}
+
+fn limits(cli: &ArgMatches) -> Limits {
+    Limits {
+        max_datastack: cli.get_one::<usize>("max-datastack").copied(),
+        max_callstack: cli.get_one::<usize>("max-callstack").copied(),
+        max_elements: cli.get_one::<usize>("max-elements").copied(),
+        max_nesting: cli.get_one::<usize>("max-nesting").copied(),
+    }
+}
+
+fn prelude(cli: &ArgMatches) -> Prelude {
+    match (
+        cli.get_flag("no-prelude"),
+        cli.get_one::<PathBuf>("prelude"),
+    ) {
+        (true, _) => Prelude::None,
+        (false, Some(path)) => Prelude::File(path.clone()),
+        (false, None) => Prelude::Embedded,
+    }
+}
+
+fn cache(cli: &ArgMatches) -> Option<Cache> {
+    match cli.get_flag("no-cache") {
+        true => None,
+        false => cli
+            .get_one::<PathBuf>("cache-dir")
+            .cloned()
+            .or_else(Cache::default_dir)
+            .map(Cache::new),
+    }
+}
+
+fn environment(cli: &ArgMatches) -> Environment {
+    match cli.get_flag("deterministic") {
+        true => Environment::Deterministic {
+            seed: *cli.get_one::<u64>("seed").unwrap(),
         },
+        false => Environment::Live,
+    }
+}
 
-        _ => panic!("invalid level"),
-    };
+fn io(cli: &ArgMatches) -> Rc<dyn Io> {
+    if let Some(path) = cli.get_one::<PathBuf>("record") {
+        match RecordingIo::create(Rc::new(OsIo), path) {
+            Ok(recorder) => return Rc::new(recorder),
+            Err(err) => {
+                eprintln!(
+                    "{} cannot create {}: {err}",
+                    "Error:".bold().red(),
+                    path.display()
+                );
+                exit(1)
+            }
+        }
+    }
+    if let Some(path) = cli.get_one::<PathBuf>("replay") {
+        match ReplayIo::open(Rc::new(OsIo), path) {
+            Ok(replayer) => return Rc::new(replayer),
+            Err(err) => {
+                eprintln!(
+                    "{} cannot read {}: {err}",
+                    "Error:".bold().red(),
+                    path.display()
+                );
+                exit(1)
+            }
+        }
+    }
+
+    Rc::new(OsIo)
+}
 
-    int1.datastack = new_datastack;
-    if level < 4 {
-        let mut int2 = int1.get_dict().func();
-        int2.datastack.push(StackElement::SubStack(Vec::new()));
-        int2.swap().apply()
+fn capabilities(cli: &ArgMatches) -> Capabilities {
+    let mut capabilities = if cli.get_flag("no-io") {
+        Capabilities::no_io()
     } else {
-        int1.datastack.push(StackElement::SubStack(Vec::new()));
-        int1.swap().apply()
+        Capabilities::default()
+    };
+    if cli.get_flag("read-only") && capabilities.files == FileAccess::ReadWrite {
+        capabilities.files = FileAccess::ReadOnly;
+    }
+    if cli.get_flag("no-stdin") {
+        capabilities.stdin = false;
     }
+    capabilities.root = cli.get_one::<PathBuf>("root").cloned();
+
+    capabilities
 }
 
 fn load_program_data() -> Command {
     Command::new("Consize Rust")
         .version("0.1.0")
         .about("This is a Rust implementation of the consize programming language, incorporating a few performance enhancements. Some work better, some worse.")
-        .args([arg!(code: <code> "Consize code to execute, has to be in double quotes. The prelude has been preloaded"), 
-               arg!(level: -l --level <lvl> "Optimization level. \n\t0: Default. Without any optimizations. Just vanilla consize. \n\t1: All prelude functions have been expanded to only contain primitives. \n\t2: All primitive functions are replaced by rust functions. \n\t3: All remaining words and quotations are replaced by functions. \n\t4: Consize is now executed as one big function composition. \nLevel 3 and 4 are not working correctly, refer to the documentation for more details."),
+        .args([arg!(program: [program] "Consize source file to run, - reads it from stdin. A first line starting with #! is skipped").required_unless_present_any(["expression", "dump-bootimage"]),
+               arg!(args: [args] ... "Arguments for the program, which the word args pushes as a stack").trailing_var_arg(true).allow_hyphen_values(true),
+               arg!(expression: -e <code> "Consize code to run instead of a file, can be given several times. All other arguments are passed to the code then").action(ArgAction::Append),
+               arg!(level: -l --level <lvl> "Optimization level. \n\t0: Default. Without any optimizations. Just vanilla consize. \n\t1: Prelude functions have been expanded within the inlining budget. \n\t2: All primitive functions are replaced by rust functions. \n\t3: All remaining words are replaced by functions, quotations are compiled when they are called. \n\t4: Runs of primitives that do not touch the callstack are composed into single functions. \n\t5: Definitions and quotations are compiled to bytecode and run by a virtual machine.").value_parser(value_parser!(u8).range(0..=MAX_LEVEL as i64)),
+               arg!(passes: --passes <list> "Comma separated optimization passes to run instead of a level, in order. Available: inline, resolve, compile, compose, fold, bytecode, and jit if built with the jit feature. Level 2 is inline,resolve, level 4 is compile,compose").value_parser(|passes: &str| Pipeline::parse(passes).map(|_| passes.to_string())).conflicts_with("level"),
+               arg!(emit: --emit <ir> "Print the definitions and the code as the given level transforms them instead of running the code. Rust functions are shown in angle brackets").value_parser(["level1", "level2", "level3", "level4"]).conflicts_with_all(["level", "passes", "verify"]),
+               arg!(transpile: --transpile <dir> "Write a Rust crate to <dir> that runs the code at level 2 as a standalone executable instead of running it").value_parser(value_parser!(PathBuf)).conflicts_with_all(["level", "passes", "emit", "verify"]),
+               arg!(runtime: --runtime <dir> "Make the crate written by --transpile depend on the library in <dir> instead of the checkout this binary was built from").value_parser(value_parser!(PathBuf)).requires("transpile"),
+               arg!(emit_word: --"emit-word" <name> "Only print the definition of <name> with --emit").id("emit-word").requires("emit"),
+               arg!(inline_budget: --"inline-budget" <n> "Levels 1 and 2 only inline self-defined words whose inlined body has at most <n> elements, less for words used in few places").id("inline-budget").value_parser(value_parser!(usize)).default_value("64"),
+               arg!(adaptive: --adaptive <lvl> "Start at level 0 and promote self-defined words to level 2 or 4 once they are called --hot-calls times, unless they use call/cc, continue or get-dict").value_parser(["2", "4"]).conflicts_with_all(["level", "passes", "emit", "transpile", "verify"]),
+               arg!(hot_calls: --"hot-calls" <n> "How often --adaptive waits for a word to be called before promoting it").id("hot-calls").value_parser(value_parser!(u32)).default_value("100").requires("adaptive"),
+               arg!(prelude: --prelude <file> "Load the prelude from <file> instead of the one built into the binary").value_parser(value_parser!(PathBuf)),
+               arg!(no_prelude: --"no-prelude" "Start with nothing but the primitives").id("no-prelude").conflicts_with("prelude"),
+               arg!(preload: --preload <file> "Run <file> after the prelude and before the code, can be given several times").value_parser(value_parser!(PathBuf)).action(ArgAction::Append),
+               arg!(bootimage: --bootimage <file> "Boot from the primitives and the bootimage <file> only instead of loading the prelude. Run \\ prelude-plain.txt run to load the prelude on top").conflicts_with_all(["prelude", "no-prelude", "cache-dir", "no-cache"]),
+               arg!(dump_bootimage: --"dump-bootimage" [file] "Write the words the prelude needs to boot to <file>, bootimage.txt by default, instead of running code").id("dump-bootimage").default_missing_value("bootimage.txt").conflicts_with_all(["program", "expression", "bootimage", "no-prelude", "emit", "transpile", "verify", "adaptive"]),
+               arg!(cache_dir: --"cache-dir" <dir> "Where to keep the prelude as it is loaded and optimised for levels 0 to 2, so later runs start faster. Defaults to $CONSIZE_CACHE_DIR or consize-interpreter in the user's cache directory").id("cache-dir").value_parser(value_parser!(PathBuf)),
+               arg!(no_cache: --"no-cache" "Always load and optimise the prelude from scratch").id("no-cache").conflicts_with("cache-dir"),
+               arg!(verify: --verify "Also run the code at level 0 and fail if the chosen level computes a different datastack, output or error"),
+               arg!(max_datastack: --"max-datastack" <n> "Abort with an error once the datastack holds more than <n> elements").id("max-datastack").value_parser(value_parser!(usize)),
+               arg!(max_callstack: --"max-callstack" <n> "Abort with an error once the callstack holds more than <n> elements").id("max-callstack").value_parser(value_parser!(usize)),
+               arg!(max_elements: --"max-elements" <n> "Abort with an error once both stacks together, including nested stacks and maps, hold more than <n> elements").id("max-elements").value_parser(value_parser!(usize)),
+               arg!(max_nesting: --"max-nesting" <n> "Abort with an error once primitives like apply nest deeper than <n> native calls").id("max-nesting").value_parser(value_parser!(usize)).default_value("100000"),
+               arg!(no_io: --"no-io" "Forbid all file access and reading from stdin").id("no-io"),
+               arg!(read_only: --"read-only" "Allow reading files but forbid spit and spit-on").id("read-only"),
+               arg!(root: --root <dir> "Only allow file access within <dir>, relative paths are resolved against it").value_parser(value_parser!(PathBuf)),
+               arg!(no_stdin: --"no-stdin" "Forbid read-line").id("no-stdin"),
+               arg!(record: --record <file> "Log the results of read-line, slurp, spit, spit-on and current-time-millis to <file>").value_parser(value_parser!(PathBuf)),
+               arg!(replay: --replay <file> "Feed the results logged by --record back instead of reading stdin, files or the clock").value_parser(value_parser!(PathBuf)).conflicts_with("record"),
+               arg!(deterministic: --deterministic "Make runs reproducible: current-time-millis counts its own calls and operating-system always returns consize"),
+               arg!(seed: --seed <n> "Seed for randomness in deterministic mode, also the time the virtual clock starts at").value_parser(value_parser!(u64)).default_value("0").requires("deterministic"),
             ])
 }
@@ -0,0 +1,187 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::BTreeMap,
+    fmt,
+    ops::Deref,
+    rc::Rc,
+    slice,
+};
+
+use crate::{
+    pragma::Pragmas,
+    preprocessor::{Compile, Compose, Fold, Inline, InlineBudget, Resolve},
+    stack_element::{Funct, StackElement},
+    vm::Bytecode,
+};
+
+/// A transformation of quotations. The optimiser runs the passes of a
+/// [`Pipeline`] in turn on every definition and on the program itself.
+pub trait Pass {
+    /// The name used by `--passes`.
+    fn name(&self) -> &'static str;
+
+    /// Transforms `words`, given in callstack order. `word` is the name of
+    /// the definition they belong to, or empty for other quotations.
+    fn run(&self, word: &str, words: &[StackElement], context: &Context) -> Vec<StackElement>;
+
+    /// Whether the pass turns words into Rust functions. Definitions are then
+    /// stored as compiled functions that keep their source, and `call` and
+    /// `func` run the pipeline on their quotation first.
+    fn compiles(&self) -> bool {
+        false
+    }
+}
+
+/// Everything a pass may look at besides the quotation.
+pub struct Context<'a> {
+    pub dictionary: &'a Rc<BTreeMap<String, Rc<Funct>>>,
+    pub budget: InlineBudget,
+    pragmas: &'a BTreeMap<String, Pragmas>,
+    uses: OnceCell<BTreeMap<String, usize>>,
+    /// The inlined bodies of words, shared by every quotation of one
+    /// optimisation run.
+    pub(crate) inlined: RefCell<BTreeMap<String, Vec<StackElement>>>,
+}
+
+static NO_PRAGMAS: BTreeMap<String, Pragmas> = BTreeMap::new();
+
+impl<'a> Context<'a> {
+    pub fn new(dictionary: &'a Rc<BTreeMap<String, Rc<Funct>>>, budget: InlineBudget) -> Self {
+        Self {
+            dictionary,
+            budget,
+            pragmas: &NO_PRAGMAS,
+            uses: OnceCell::new(),
+            inlined: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn with_pragmas(mut self, pragmas: &'a BTreeMap<String, Pragmas>) -> Self {
+        self.pragmas = pragmas;
+        self
+    }
+
+    /// The pragmas the definition of `word` was annotated with.
+    pub fn pragmas(&self, word: &str) -> Pragmas {
+        self.pragmas.get(word).copied().unwrap_or_default()
+    }
+
+    /// How often `word` is used in the self-defined words of the dictionary.
+    pub fn uses(&self, word: &str) -> usize {
+        fn count(words: &[StackElement], uses: &mut BTreeMap<String, usize>) {
+            for se in words {
+                match se {
+                    StackElement::Word(w) => *uses.entry(w.clone()).or_default() += 1,
+                    StackElement::SubStack(ss) => count(ss, uses),
+                    StackElement::Map(m) => {
+                        m.iter().for_each(|(_, v)| count(slice::from_ref(v), uses))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let uses = self.uses.get_or_init(|| {
+            let mut uses = BTreeMap::new();
+            for f in self.dictionary.values() {
+                if let Funct::SelfDefined(StackElement::SubStack(body)) = f.deref() {
+                    count(body, &mut uses);
+                }
+            }
+            uses
+        });
+        uses.get(word).copied().unwrap_or(0)
+    }
+}
+
+/// Every pass `--passes` knows about.
+pub fn available() -> Vec<Rc<dyn Pass>> {
+    vec![
+        Rc::new(Inline),
+        Rc::new(Resolve),
+        Rc::new(Compile),
+        Rc::new(Compose),
+        Rc::new(Fold),
+        Rc::new(Bytecode::default()),
+        #[cfg(feature = "jit")]
+        Rc::new(Bytecode::jit()),
+    ]
+}
+
+/// The highest optimisation level, see [`Pipeline::level`].
+pub const MAX_LEVEL: u8 = 5;
+
+/// The passes to run, in order. An empty pipeline leaves Consize untouched.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    passes: Vec<Rc<dyn Pass>>,
+}
+
+impl Pipeline {
+    pub fn new(passes: Vec<Rc<dyn Pass>>) -> Self {
+        Self { passes }
+    }
+
+    /// The passes behind an optimisation level. Panics unless `level` is at
+    /// most [`MAX_LEVEL`], as do the functions in [`runner`](crate::runner)
+    /// and [`verify`](crate::verify) that take a level.
+    pub fn level(level: u8) -> Self {
+        let passes: Vec<Rc<dyn Pass>> = match level {
+            0 => vec![],
+            1 => vec![Rc::new(Inline)],
+            2 => vec![Rc::new(Inline), Rc::new(Resolve)],
+            3 => vec![Rc::new(Compile)],
+            4 => vec![Rc::new(Compile), Rc::new(Compose)],
+            5 => vec![Rc::new(Bytecode::default())],
+            _ => panic!("level has to be between 0 and {MAX_LEVEL}"),
+        };
+        Self::new(passes)
+    }
+
+    /// Parses a comma separated list of pass names like `inline,resolve`.
+    pub fn parse(names: &str) -> Result<Self, String> {
+        let passes = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                available()
+                    .into_iter()
+                    .find(|pass| pass.name() == name)
+                    .ok_or_else(|| {
+                        let known: Vec<_> = available().iter().map(|p| p.name()).collect();
+                        format!("unknown pass `{name}`, expected {}", known.join(", "))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(passes))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    pub fn compiles(&self) -> bool {
+        self.passes.iter().any(|pass| pass.compiles())
+    }
+
+    /// Runs every pass on `words` in turn.
+    pub fn run(&self, word: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        self.passes.iter().fold(words.to_vec(), |words, pass| {
+            pass.run(word, &words, context)
+        })
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.passes.iter().map(|pass| pass.name()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pipeline({self})")
+    }
+}
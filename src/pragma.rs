@@ -0,0 +1,70 @@
+use crate::{
+    pass::{Pipeline, MAX_LEVEL},
+    stack_element::StackElement,
+};
+
+/// How the optimiser treats a definition, as annotated by `pragmas` once it
+/// is defined:
+///
+/// ```text
+/// : square ( n -- n ) dup * ;
+/// \ square ( inline ) pragmas
+/// \ [ ( no-optimise ) pragmas
+/// \ fib ( level 4 ) pragmas
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Pragmas {
+    /// `inline`: inlined into its callers whatever the budget says.
+    pub inline: bool,
+    /// `no-optimise`: kept as written and never inlined or compiled into
+    /// its callers.
+    pub no_optimise: bool,
+    /// `level n`: optimised at level `n` instead of the level of the rest of
+    /// the dictionary, and never inlined or compiled into its callers.
+    pub level: Option<u8>,
+}
+
+impl Pragmas {
+    /// Reads the pragmas in `stack`, the first on top.
+    pub fn parse(stack: &[StackElement]) -> Result<Self, String> {
+        let mut pragmas = Self::default();
+        let mut words = stack.iter().rev();
+        while let Some(se) = words.next() {
+            match se {
+                StackElement::Word(w) if w == "inline" => pragmas.inline = true,
+                StackElement::Word(w) if w == "no-optimise" => pragmas.no_optimise = true,
+                StackElement::Word(w) if w == "level" => {
+                    pragmas.level = match words.next() {
+                        Some(StackElement::Word(n)) => n.parse().ok().filter(|n| *n <= MAX_LEVEL),
+                        _ => None,
+                    };
+                    if pragmas.level.is_none() {
+                        return Err(format!(
+                            "the pragma level needs a level between 0 and {MAX_LEVEL}"
+                        ));
+                    }
+                }
+                se => return Err(format!("unknown pragma {se}")),
+            }
+        }
+        if pragmas.inline && !pragmas.inlinable() {
+            return Err("a word cannot be inlined and kept from being optimised".to_string());
+        }
+        Ok(pragmas)
+    }
+
+    /// Whether other definitions may take over the body of the word.
+    pub fn inlinable(&self) -> bool {
+        !self.no_optimise && self.level.is_none()
+    }
+
+    /// The pipeline the word is optimised by when the dictionary is optimised
+    /// by `pipeline`.
+    pub fn pipeline(&self, pipeline: &Pipeline) -> Pipeline {
+        match (self.no_optimise, self.level) {
+            (true, _) => Pipeline::default(),
+            (false, Some(level)) => Pipeline::level(level),
+            (false, None) => pipeline.clone(),
+        }
+    }
+}
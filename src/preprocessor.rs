@@ -1,127 +1,623 @@
 use core::panic;
-use std::{collections::BTreeMap, ops::Deref, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    ops::Deref,
+    ptr,
+    rc::{Rc, Weak},
+    slice,
+};
 
 use crate::{
     interpreter::Interpreter,
-    stack_element::{BuiltIn, Funct, StackElement},
+    pass::{Context, Pass, Pipeline},
+    pragma::Pragmas,
+    stack_element::{count_elements, reify, BuiltIn, Funct, StackElement},
 };
 
-pub fn optimise_dict(mut int: Interpreter, lvl: u8) -> Interpreter {
-    let dictionary = int.dictionary.to_owned();
-    let mut new_dict = BTreeMap::new();
+/// Primitives that read or replace the callstack. Level 4 never composes them
+/// with other code, so the continuation they operate on is always explicit.
+pub(crate) const CONTINUATION_WORDS: [&str; 6] =
+    ["call", "call/cc", "continue", "\\", "stepcc", "run"];
 
-    dictionary.iter().for_each(|(n, l)| {
-        new_dict.insert(
-            n.clone(),
-            match l.deref() {
-                Funct::BuiltIn(bi) => Rc::new(Funct::BuiltIn(bi.clone())),
-                Funct::SelfDefined(sd) => match sd {
-                    StackElement::SubStack(ss) => {
-                        match lvl {
-                            1 => Rc::new(Funct::SelfDefined(StackElement::SubStack(
-                                call_fn_step_1(n.clone(), ss, &dictionary),
-                            ))),
-                            2 => Rc::new(Funct::SelfDefined(StackElement::SubStack(
-                                call_fn_step_2(n.clone(), ss, &dictionary),
-                            ))),
-                            3 => Rc::new(Funct::SelfDefined(StackElement::SubStack(
-                                call_fn_step_3(n.clone(), ss, &dictionary),
-                            ))),
-                            4 => {
-                                Rc::new(Funct::BuiltIn(call_fn_step_4(n.clone(), ss, &dictionary)))
-                            }
-                            _ => panic!("optimization level has to be between 0 and 4"),
-                        }
-                    }
-                    StackElement::Word(w) => Rc::new(Funct::SelfDefined(StackElement::SubStack(
-                        preprocess(n.clone(), &[StackElement::Word(w.clone())], &dictionary),
-                    ))),
-                    _ => unimplemented!(),
-                },
-            },
-        );
-    });
+/// The dictionary [`optimise_dict`] started from, with the primitives of
+/// the pipeline, so [`reoptimise`] can redo the work for changed definitions.
+#[derive(Debug)]
+pub struct Optimisation {
+    pub pipeline: Pipeline,
+    pub source: Rc<BTreeMap<String, Rc<Funct>>>,
+}
 
-    int.dictionary = Rc::new(new_dict);
+pub fn optimise_dict(mut int: Interpreter, pipeline: &Pipeline) -> Interpreter {
+    let source = match pipeline.compiles() {
+        true => Rc::new(with_compiling_primitives(&int.dictionary, pipeline)),
+        false => int.dictionary.to_owned(),
+    };
+    let context = Context::new(&source, int.inline_budget).with_pragmas(&int.pragmas);
+
+    int.dictionary = Rc::new(
+        source
+            .iter()
+            .map(|(n, l)| (n.clone(), optimise_entry(n, l, &context, pipeline)))
+            .collect(),
+    );
+    int.optimisation = Some(Rc::new(Optimisation {
+        pipeline: pipeline.clone(),
+        source,
+    }));
 
     int
 }
 
+/// Installs `dictionary` after a `set-dict`. If the current dictionary is
+/// optimised, every changed definition is optimised as well, together with
+/// all definitions that use a changed word and may therefore hold a stale
+/// inlined copy of it. All other definitions are kept as they are.
+pub fn reoptimise(
+    mut int: Interpreter,
+    mut dictionary: BTreeMap<String, Rc<Funct>>,
+) -> Interpreter {
+    let Some(optimisation) = int.optimisation.clone() else {
+        int.dictionary = Rc::new(dictionary);
+        return int;
+    };
+
+    let mut source = (*optimisation.source).clone();
+    let mut changed = BTreeSet::new();
+    for (name, f) in &dictionary {
+        if !int
+            .dictionary
+            .get(name)
+            .is_some_and(|old| Rc::ptr_eq(old, f))
+        {
+            source.insert(name.clone(), unoptimised(f));
+            changed.insert(name.clone());
+        }
+    }
+    for name in int.dictionary.keys() {
+        if !dictionary.contains_key(name) {
+            source.remove(name);
+            changed.insert(name.clone());
+        }
+    }
+
+    if !changed.is_empty() {
+        let source = Rc::new(source);
+        let context = Context::new(&source, int.inline_budget).with_pragmas(&int.pragmas);
+        // Passes treat unknown words and mappings as literals as long as
+        // `read-word` and `read-mapping` do nothing.
+        let affected = match ["read-word", "read-mapping"]
+            .iter()
+            .any(|w| changed.contains(*w))
+        {
+            true => source.keys().cloned().collect(),
+            false => dependents(&source, changed),
+        };
+        for name in affected {
+            if let Some(f) = source.get(&name) {
+                let f = optimise_entry(&name, f, &context, &optimisation.pipeline);
+                dictionary.insert(name, f);
+            }
+        }
+        int.optimisation = Some(Rc::new(Optimisation {
+            pipeline: optimisation.pipeline.clone(),
+            source,
+        }));
+    }
+    int.dictionary = Rc::new(dictionary);
+
+    int
+}
+
+/// The optimised form of the definition `l` of `n`. Its pragmas may ask for
+/// another pipeline or for none at all.
+pub(crate) fn optimise_entry(
+    n: &str,
+    l: &Rc<Funct>,
+    context: &Context,
+    pipeline: &Pipeline,
+) -> Rc<Funct> {
+    let pipeline = &context.pragmas(n).pipeline(pipeline);
+    let Some(body) = definition(l).filter(|_| !pipeline.is_empty()) else {
+        return l.clone();
+    };
+    let ops = pipeline.run(n, &body, context);
+    match pipeline.compiles() {
+        true => Rc::new(Funct::Compiled(body, push_to_cs(ops))),
+        false => Rc::new(Funct::SelfDefined(StackElement::SubStack(ops))),
+    }
+}
+
+/// The body of a self-defined word.
+fn definition(f: &Funct) -> Option<Vec<StackElement>> {
+    match f {
+        Funct::SelfDefined(StackElement::SubStack(ss)) => Some(ss.clone()),
+        Funct::SelfDefined(w @ StackElement::Word(_)) => Some(vec![w.clone()]),
+        _ => None,
+    }
+}
+
+/// The self-defined words of an optimised interpreter as its pipeline
+/// transforms them, or only `word` if given. Compiled definitions hide their
+/// code in a closure, so it is produced again from the source.
+pub fn transformed(int: &Interpreter, word: Option<&str>) -> Vec<(String, Vec<StackElement>)> {
+    let (source, pipeline) = match &int.optimisation {
+        Some(optimisation) => (&optimisation.source, optimisation.pipeline.clone()),
+        None => (&int.dictionary, Pipeline::default()),
+    };
+    let context = Context::new(source, int.inline_budget).with_pragmas(&int.pragmas);
+    source
+        .iter()
+        .filter(|(n, _)| word.is_none_or(|w| w == n.as_str()))
+        .filter_map(|(n, f)| {
+            let pipeline = context.pragmas(n).pipeline(&pipeline);
+            Some((n.clone(), pipeline.run(n, &definition(f)?, &context)))
+        })
+        .collect()
+}
+
+/// The level 0 equivalent of a dictionary entry.
+fn unoptimised(f: &Rc<Funct>) -> Rc<Funct> {
+    match f.deref() {
+        Funct::Compiled(src, _) => Rc::new(Funct::SelfDefined(StackElement::SubStack(src.clone()))),
+        _ => f.clone(),
+    }
+}
+
+/// `changed` and all words whose definitions use one of them, directly or
+/// through other definitions.
+pub(crate) fn dependents(
+    dictionary: &BTreeMap<String, Rc<Funct>>,
+    changed: BTreeSet<String>,
+) -> BTreeSet<String> {
+    fn uses<'a>(
+        name: &'a str,
+        words: &'a [StackElement],
+        users: &mut BTreeMap<&'a str, Vec<&'a str>>,
+    ) {
+        for se in words {
+            match se {
+                StackElement::Word(w) => users.entry(w).or_default().push(name),
+                StackElement::SubStack(ss) => uses(name, ss, users),
+                StackElement::Map(m) => {
+                    for (_, v) in m {
+                        uses(name, slice::from_ref(v), users);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let mut users = BTreeMap::new();
+    for (name, f) in dictionary {
+        if let Funct::SelfDefined(sd) = f.deref() {
+            uses(name, slice::from_ref(sd), &mut users);
+        }
+    }
+
+    let mut found = changed.clone();
+    let mut todo: Vec<String> = changed.into_iter().collect();
+    while let Some(word) = todo.pop() {
+        for user in users.get(word.as_str()).into_iter().flatten() {
+            if found.insert(user.to_string()) {
+                todo.push(user.to_string());
+            }
+        }
+    }
+
+    found
+}
+
+/// Self-defined words nested deeper than this are left as calls, which keeps
+/// level 1 and 2 definitions from growing without bound.
+const MAX_INLINE_DEPTH: usize = 32;
+
+/// Words used in this many places get the full [`InlineBudget`].
+const HOT_CALLS: usize = 4;
+
+/// Decides which self-defined words levels 1 and 2 inline. A word is inlined
+/// if its body, itself inlined within the budget, has at most `max_size`
+/// elements. Words used in fewer than four places of the dictionary are cold
+/// and only get a share of the budget, recursive words are never inlined.
+/// [`Pragmas`] override the budget for single words.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InlineBudget {
+    pub max_size: usize,
+}
+
+impl InlineBudget {
+    /// Inlines everything that is not recursive.
+    pub const UNLIMITED: Self = Self {
+        max_size: usize::MAX,
+    };
+}
+
+impl Default for InlineBudget {
+    fn default() -> Self {
+        Self { max_size: 64 }
+    }
+}
+
 pub fn preprocess(
     word: String,
     words: &[StackElement],
     dictionary: &Rc<BTreeMap<String, Rc<Funct>>>,
+    budget: InlineBudget,
+    pragmas: &BTreeMap<String, Pragmas>,
 ) -> Vec<StackElement> {
-    let new_words: Vec<StackElement> = words
-        .iter()
-        .enumerate()
-        .flat_map(|(i, se)| match se.clone() {
-            StackElement::Word(w) => {
-                if w != word
-                    || (i + 2 < words.len()
-                        && words[i + 1] == StackElement::Word("\\".to_string())
-                        && words[i + 2] != StackElement::Word("\\".to_string()))
+    Inline.run(
+        &word,
+        words,
+        &Context::new(dictionary, budget).with_pragmas(pragmas),
+    )
+}
+
+/// Level 1: inlines self-defined words within the [`InlineBudget`].
+pub struct Inline;
+
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, word: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        let inliner = Inliner {
+            context,
+            depends: Cell::new(usize::MAX),
+        };
+        inliner.inline(words, &mut vec![word.to_string()])
+    }
+}
+
+struct Inliner<'a> {
+    context: &'a Context<'a>,
+    /// The lowest position in `expanding` the current expansion stopped at.
+    depends: Cell<usize>,
+}
+
+impl Inliner<'_> {
+    /// The size up to which `word` is inlined. The program using the word
+    /// counts as one more place.
+    fn allowance(&self, word: &str) -> usize {
+        let calls = self.context.uses(word) + 1;
+        let budget = self.context.budget;
+        budget.max_size.saturating_mul(calls.min(HOT_CALLS)) / HOT_CALLS
+    }
+
+    fn depends_on(&self, position: usize) {
+        self.depends.set(self.depends.get().min(position));
+    }
+
+    /// The inlined body of `word`, memoised unless its expansion stopped at a
+    /// word that was already being expanded outside of it or at the depth
+    /// limit, since the body then depends on where `word` is used.
+    fn body(
+        &self,
+        word: &str,
+        words: &[StackElement],
+        expanding: &mut Vec<String>,
+    ) -> Vec<StackElement> {
+        if let Some(body) = self.context.inlined.borrow().get(word) {
+            return body.clone();
+        }
+        let outer = self.depends.replace(usize::MAX);
+        let position = expanding.len();
+        expanding.push(word.to_string());
+        let body = self.inline(words, expanding);
+        expanding.pop();
+        let depends = self.depends.replace(outer);
+        if depends >= position {
+            let mut inlined = self.context.inlined.borrow_mut();
+            inlined.insert(word.to_string(), body.clone());
+        }
+        self.depends_on(depends);
+        body
+    }
+
+    /// Inlines self-defined words that fit the budget or are annotated with
+    /// `inline`, unless they are already being expanded, so recursive and
+    /// mutually recursive words stay calls.
+    fn inline(&self, words: &[StackElement], expanding: &mut Vec<String>) -> Vec<StackElement> {
+        let new_words: Vec<StackElement> = words
+            .iter()
+            .enumerate()
+            .flat_map(|(i, se)| {
+                // Escaped elements are data, escaped stacks and mappings included.
+                if (i + 2 < words.len()
+                    && words[i + 1] == StackElement::Word("\\".to_string())
+                    && words[i + 2] != StackElement::Word("\\".to_string()))
                     || (i + 2 == words.len()
                         && words[i + 1] == StackElement::Word("\\".to_string()))
                 {
-                    if (i + 2 < words.len()
-                        && words[i + 1] == StackElement::Word("\\".to_string())
-                        && words[i + 2] != StackElement::Word("\\".to_string()))
-                        || (i + 2 == words.len()
-                            && words[i + 1] == StackElement::Word("\\".to_string()))
-                    {
-                        return vec![StackElement::Fun(Rc::new(Funct::BuiltIn(pull_to_ds(
-                            se.clone(),
-                        ))))];
+                    return vec![escape(se.clone())];
+                }
+                match se.clone() {
+                    StackElement::Word(w) => {
+                        match self.context.dictionary.get(&w).map(|f| f.deref()) {
+                            Some(Funct::SelfDefined(StackElement::SubStack(sd)))
+                                if self.context.pragmas(&w).inlinable() =>
+                            {
+                                if let Some(i) = expanding.iter().position(|e| *e == w) {
+                                    self.depends_on(i);
+                                    return vec![se.to_owned()];
+                                }
+                                if expanding.len() >= MAX_INLINE_DEPTH {
+                                    self.depends_on(0);
+                                    return vec![se.to_owned()];
+                                }
+                                let body = self.body(&w, sd, expanding);
+                                match self.context.pragmas(&w).inline
+                                    || count_elements(&body) <= self.allowance(&w)
+                                {
+                                    true => body,
+                                    false => vec![se.to_owned()],
+                                }
+                            }
+                            Some(Funct::SelfDefined(_))
+                            | Some(Funct::BuiltIn(_))
+                            | Some(Funct::Compiled(..))
+                            | None => vec![se.to_owned()],
+                        }
                     }
-                    match dictionary.get(&w) {
-                        Some(fun) => match fun.deref() {
-                            Funct::BuiltIn(_) => vec![se.to_owned()],
-                            Funct::SelfDefined(sd) => match sd {
-                                StackElement::SubStack(sd) => preprocess(w.clone(), sd, dictionary),
-                                _ => unimplemented!(),
-                            },
-                        },
-                        None => vec![se.to_owned()],
+                    StackElement::SubStack(ss) => {
+                        vec![StackElement::SubStack(self.inline(&ss, expanding))]
+                    }
+                    StackElement::Map(m) => {
+                        vec![StackElement::Map(
+                            m.into_iter()
+                                .map(|(k, v)| {
+                                    (
+                                        k,
+                                        match v {
+                                            StackElement::SubStack(ss) => {
+                                                StackElement::SubStack(self.inline(&ss, expanding))
+                                            }
+                                            v => v,
+                                        },
+                                    )
+                                })
+                                .collect(),
+                        )]
                     }
+                    _ => vec![se.to_owned()],
+                }
+            })
+            .collect();
+        new_words
+            .into_iter()
+            .filter(|i| i != &StackElement::Word("\\".to_string()))
+            .collect()
+    }
+}
+
+/// Level 2: replaces primitives by their Rust functions.
+pub struct Resolve;
+
+impl Pass for Resolve {
+    fn name(&self) -> &'static str {
+        "resolve"
+    }
+
+    fn run(&self, _: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        replace_with_fun(words, context.dictionary)
+    }
+}
+
+/// Simplifies code: pure primitives applied to literals are evaluated, and
+/// `swap swap`, `dup drop` and `rot rot rot` are removed. Primitives that
+/// would fail, like a division by zero, are left for the runtime.
+pub struct Fold;
+
+impl Pass for Fold {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, _: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        fold(words, context.dictionary)
+    }
+}
+
+/// Primitives that only depend on and change the top of the datastack.
+pub(crate) const PURE: [&str; 14] = [
+    "dup", "drop", "swap", "rot", "+", "-", "*", "div", "mod", "<", ">", "==", "<=", ">=",
+];
+
+/// Sequences of pure primitives that leave the datastack as it was.
+const IDENTITIES: [&[&str]; 3] = [&["swap", "swap"], &["dup", "drop"], &["rot", "rot", "rot"]];
+
+/// A piece of folded code together with its source in reading order.
+enum Folded {
+    /// Pushes `value` onto the datastack.
+    Literal(StackElement, Vec<StackElement>),
+    Pure(String, Vec<StackElement>),
+    Other(Vec<StackElement>),
+}
+
+impl Folded {
+    fn source(self) -> Vec<StackElement> {
+        match self {
+            Self::Literal(_, source) | Self::Pure(_, source) | Self::Other(source) => source,
+        }
+    }
+}
+
+fn fold(words: &[StackElement], dictionary: &Rc<BTreeMap<String, Rc<Funct>>>) -> Vec<StackElement> {
+    let primitive = |w: &str| {
+        matches!(
+            dictionary.get(w).map(|f| f.deref()),
+            Some(Funct::BuiltIn(_))
+        )
+    };
+    // Unknown words are pushed and handed to `read-word`, which the prelude
+    // defines to do nothing.
+    let read_word_is_noop = matches!(
+        dictionary.get("read-word").map(|f| f.deref()),
+        Some(Funct::SelfDefined(StackElement::SubStack(body))) if body.is_empty()
+    );
+    let is_number =
+        |w: &str| read_word_is_noop && w.parse::<isize>().is_ok() && !dictionary.contains_key(w);
+
+    // A quotation stays as written unless `call` runs it right away, as
+    // other words may look at its elements.
+    let is_call = |se: &StackElement| match se {
+        StackElement::Word(w) => w == "call",
+        StackElement::Fun(f) => matches!(
+            f.deref(),
+            Funct::Compiled(src, _) if matches!(src.as_slice(), [StackElement::Word(w)] if w == "call")
+        ),
+        _ => false,
+    };
+
+    let mut code: Vec<Folded> = Vec::new();
+    let mut rest = words.iter().rev().peekable();
+    while let Some(se) = rest.next() {
+        let folded = match se {
+            StackElement::Word(w) if w == "\\" && primitive(w) => match rest.next() {
+                Some(next) => Folded::Literal(next.clone(), vec![se.clone(), next.clone()]),
+                None => Folded::Other(vec![se.clone()]),
+            },
+            StackElement::Word(w) if is_number(w) => Folded::Literal(se.clone(), vec![se.clone()]),
+            StackElement::Word(w) if PURE.contains(&w.as_str()) && primitive(w) => {
+                Folded::Pure(w.clone(), vec![se.clone()])
+            }
+            StackElement::SubStack(ss) if rest.peek().is_some_and(|next| is_call(next)) => {
+                let ss = StackElement::SubStack(fold(ss, dictionary));
+                Folded::Literal(ss.clone(), vec![ss])
+            }
+            StackElement::SubStack(_) => Folded::Literal(se.clone(), vec![se.clone()]),
+            StackElement::Nil => Folded::Literal(se.clone(), vec![se.clone()]),
+            StackElement::Fun(f) => match f.deref() {
+                Funct::Compiled(src, _) => match src.as_slice() {
+                    [x, StackElement::Word(w)] if w == "\\" => {
+                        Folded::Literal(x.clone(), vec![se.clone()])
+                    }
+                    [StackElement::Word(w)] if PURE.contains(&w.as_str()) && primitive(w) => {
+                        Folded::Pure(w.clone(), vec![se.clone()])
+                    }
+                    _ => Folded::Other(vec![se.clone()]),
+                },
+                _ => Folded::Other(vec![se.clone()]),
+            },
+            _ => Folded::Other(vec![se.clone()]),
+        };
+        match folded {
+            Folded::Pure(op, source) => {
+                if let Some(results) = evaluate(&op, &mut code, &is_number) {
+                    code.extend(results);
                 } else {
-                    vec![se.to_owned()]
+                    code.push(Folded::Pure(op, source));
+                    remove_identities(&mut code);
                 }
             }
-            StackElement::SubStack(ss) => vec![StackElement::SubStack(preprocess(
-                word.to_owned(),
-                &ss,
-                dictionary,
-            ))],
-            StackElement::Map(m) => {
-                vec![StackElement::Map(
-                    m.into_iter()
-                        .map(|(k, v)| {
-                            (
-                                k,
-                                match v {
-                                    StackElement::SubStack(ss) => StackElement::SubStack(
-                                        preprocess(word.clone(), &ss, dictionary),
-                                    ),
-                                    StackElement::Word(w) => StackElement::SubStack(preprocess(
-                                        word.clone(),
-                                        &[StackElement::Word(w)],
-                                        dictionary,
-                                    )),
-                                    _ => todo!(),
-                                },
-                            )
-                        })
-                        .collect(),
-                )]
+            folded => code.push(folded),
+        }
+    }
+
+    let mut folded: Vec<StackElement> = code.into_iter().flat_map(Folded::source).collect();
+    folded.reverse();
+    folded
+}
+
+/// Applies `op` to the literals at the end of `code` and returns the
+/// literals it leaves. Returns `None` and leaves `code` alone if there are not
+/// enough literals or the primitive would fail. Resulting numbers are written
+/// as plain words if `is_number` accepts them.
+fn evaluate(
+    op: &str,
+    code: &mut Vec<Folded>,
+    is_number: &dyn Fn(&str) -> bool,
+) -> Option<Vec<Folded>> {
+    let arity = match op {
+        "dup" | "drop" => 1,
+        "rot" => 3,
+        _ => 2,
+    };
+    let literals = code
+        .iter()
+        .rev()
+        .take_while(|f| matches!(f, Folded::Literal(..)))
+        .count();
+    if literals < arity {
+        return None;
+    }
+
+    match op {
+        "dup" | "drop" | "swap" | "rot" => {
+            let mut args = code.split_off(code.len() - arity);
+            match op {
+                "dup" => {
+                    if let Folded::Literal(value, source) = &args[0] {
+                        let copy = Folded::Literal(value.clone(), source.clone());
+                        args.push(copy);
+                    }
+                }
+                "drop" => args.clear(),
+                "swap" => args.swap(0, 1),
+                _ => args.rotate_left(1),
             }
-            _ => vec![se.to_owned()],
-        })
-        .collect();
-    new_words
-        .into_iter()
-        .filter(|i| i != &StackElement::Word("\\".to_string()))
-        .collect()
+            Some(args)
+        }
+        _ => {
+            let number = |f: &Folded| match f {
+                Folded::Literal(StackElement::Word(w), _) => w.parse::<isize>().ok(),
+                _ => None,
+            };
+            let (y, x) = (
+                number(&code[code.len() - 2])?,
+                number(&code[code.len() - 1])?,
+            );
+            let result = match op {
+                "+" => y.checked_add(x)?.to_string(),
+                "-" => y.checked_sub(x)?.to_string(),
+                "*" => y.checked_mul(x)?.to_string(),
+                "div" => y.checked_div(x)?.to_string(),
+                "mod" => y.checked_rem(x)?.to_string(),
+                "<" => truth(y < x),
+                ">" => truth(y > x),
+                "==" => truth(y == x),
+                "<=" => truth(y <= x),
+                _ => truth(y >= x),
+            };
+            code.truncate(code.len() - 2);
+            let source = match is_number(&result) {
+                true => vec![StackElement::Word(result.clone())],
+                false => vec![
+                    StackElement::Word("\\".to_string()),
+                    StackElement::Word(result.clone()),
+                ],
+            };
+            Some(vec![Folded::Literal(StackElement::Word(result), source)])
+        }
+    }
+}
+
+fn truth(b: bool) -> String {
+    match b {
+        true => "t".to_string(),
+        false => "f".to_string(),
+    }
+}
+
+/// Drops a sequence of pure primitives at the end of `code` that has no
+/// effect at all.
+fn remove_identities(code: &mut Vec<Folded>) {
+    for identity in IDENTITIES {
+        if code.len() < identity.len() {
+            continue;
+        }
+        let tail = &code[code.len() - identity.len()..];
+        if tail
+            .iter()
+            .zip(identity)
+            .all(|(f, op)| matches!(f, Folded::Pure(w, _) if w == op))
+        {
+            code.truncate(code.len() - identity.len());
+            return;
+        }
+    }
 }
 
 fn replace_with_fun(
@@ -130,11 +626,10 @@ fn replace_with_fun(
 ) -> Vec<StackElement> {
     words
         .iter()
-        .enumerate()
-        .map(|(_, se)| match se {
+        .map(|se| match se {
             StackElement::Word(ref w) => match dictionary.get(w) {
                 Some(f) => match f.deref() {
-                    Funct::BuiltIn(_) => StackElement::Fun(f.clone()),
+                    Funct::BuiltIn(bi) | Funct::Compiled(_, bi) => compiled(w, bi.clone()),
                     Funct::SelfDefined(_) => se.to_owned(),
                 },
                 None => se.to_owned(),
@@ -149,7 +644,7 @@ fn replace_with_fun(
                                 StackElement::SubStack(ss) => {
                                     StackElement::SubStack(replace_with_fun(ss, dictionary))
                                 }
-                                _ => unimplemented!(),
+                                v => v.clone(),
                             },
                         )
                     })
@@ -160,48 +655,315 @@ fn replace_with_fun(
         .collect()
 }
 
-fn map_to_functions(words: &[StackElement]) -> Vec<StackElement> {
-    words
-        .iter()
-        .map(|se| match se {
-            StackElement::Word(w) => {
-                StackElement::Fun(Rc::new(Funct::BuiltIn(wrap_word(w.clone()))))
+/// Level 3: compiles a quotation. Self-defined words are inlined unless
+/// they are already being expanded or nested too deeply, primitives are
+/// resolved and all other words are wrapped in closures that look them up at
+/// runtime. Quotations and mappings are data and stay untouched. Every
+/// element keeps its source, so the code can still be captured by `call/cc`
+/// and inspected like the original quotation.
+pub struct Compile;
+
+impl Pass for Compile {
+    fn name(&self) -> &'static str {
+        "compile"
+    }
+
+    fn run(&self, word: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        expand(words, context, &mut vec![word.to_string()])
+    }
+
+    fn compiles(&self) -> bool {
+        true
+    }
+}
+
+fn expand(
+    words: &[StackElement],
+    context: &Context,
+    expanding: &mut Vec<String>,
+) -> Vec<StackElement> {
+    let mut ops = Vec::new();
+    let mut i = words.len();
+    while i > 0 {
+        i -= 1;
+        match &words[i] {
+            StackElement::Word(w) if w == "\\" && i > 0 => {
+                i -= 1;
+                ops.push(escape(words[i].clone()));
             }
-            StackElement::Fun(f) => match f.deref() {
-                Funct::BuiltIn(_) => se.to_owned(),
-                Funct::SelfDefined(_) => unimplemented!(),
+            StackElement::Word(w) => match context.dictionary.get(w).map(|f| f.deref()) {
+                Some(Funct::SelfDefined(StackElement::SubStack(body)))
+                    if !expanding.contains(w)
+                        && expanding.len() < MAX_INLINE_DEPTH
+                        && context.pragmas(w).inlinable() =>
+                {
+                    expanding.push(w.clone());
+                    ops.extend(expand(body, context, expanding).into_iter().rev());
+                    expanding.pop();
+                }
+                Some(Funct::BuiltIn(bi)) | Some(Funct::Compiled(_, bi)) => {
+                    ops.push(compiled(w, bi.clone()))
+                }
+                _ => ops.push(compiled(w, wrap_word(w.clone()))),
             },
-            StackElement::SubStack(ss) => StackElement::SubStack(map_to_functions(ss.as_slice())),
-            StackElement::Map(m) => StackElement::Map(
-                m.iter()
-                    .map(|(k, v)| {
-                        (
-                            k.clone(),
-                            match v {
-                                StackElement::SubStack(ss) => {
-                                    StackElement::SubStack(map_to_functions(ss.as_slice()))
-                                }
-                                _ => unimplemented!(),
-                            },
-                        )
-                    })
-                    .collect(),
+            se => ops.push(se.to_owned()),
+        }
+    }
+    ops.reverse();
+
+    ops
+}
+
+/// Level 4: composes every run of compiled elements that neither read nor
+/// modify the callstack into a single function. Everything else stays a
+/// separate element on the callstack.
+pub struct Compose;
+
+impl Pass for Compose {
+    fn name(&self) -> &'static str {
+        "compose"
+    }
+
+    fn run(&self, _: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        compose_runs(words.to_vec(), context.dictionary)
+    }
+}
+
+fn compose_runs(
+    ops: Vec<StackElement>,
+    dictionary: &Rc<BTreeMap<String, Rc<Funct>>>,
+) -> Vec<StackElement> {
+    let mut composed = Vec::new();
+    let mut run = Vec::new();
+    for op in escape_all(ops) {
+        match op {
+            StackElement::Fun(ref f) if is_callstack_neutral(f, dictionary) => run.push(op),
+            StackElement::SubStack(_) | StackElement::Map(_) | StackElement::Nil => run.push(
+                StackElement::Fun(Rc::new(Funct::Compiled(vec![op.clone()], pull_to_ds(op)))),
             ),
-            StackElement::Nil => {
-                StackElement::Fun(Rc::new(Funct::BuiltIn(pull_to_ds(StackElement::Nil))))
+            _ => {
+                flush_run(&mut run, &mut composed);
+                composed.push(op);
             }
-        })
-        .collect()
+        }
+    }
+    flush_run(&mut run, &mut composed);
+
+    composed
+}
+
+/// `ops` with every `\\ se` that did not go through [`Compile`] turned into
+/// the escaped element.
+fn escape_all(ops: Vec<StackElement>) -> Vec<StackElement> {
+    let mut escaped = Vec::new();
+    let mut ops = ops.into_iter().rev();
+    while let Some(op) = ops.next() {
+        match op {
+            StackElement::Word(ref w) if w == "\\" => match ops.next() {
+                Some(se) => escaped.push(escape(se)),
+                None => escaped.push(op),
+            },
+            op => escaped.push(op),
+        }
+    }
+    escaped.reverse();
+    escaped
+}
+
+fn flush_run(run: &mut Vec<StackElement>, composed: &mut Vec<StackElement>) {
+    match run.len() {
+        0 => {}
+        1 => composed.append(run),
+        _ => {
+            let source = reify(run);
+            let code = compose_functions(run);
+            composed.push(StackElement::Fun(Rc::new(Funct::Compiled(source, code))));
+            run.clear();
+        }
+    }
+}
+
+fn is_callstack_neutral(f: &Funct, dictionary: &Rc<BTreeMap<String, Rc<Funct>>>) -> bool {
+    match f {
+        Funct::Compiled(src, _) => match src.as_slice() {
+            [StackElement::Word(w)] => {
+                !CONTINUATION_WORDS.contains(&w.as_str())
+                    && matches!(
+                        dictionary.get(w).map(|f| f.deref()),
+                        Some(Funct::BuiltIn(_))
+                    )
+            }
+            [_, StackElement::Word(w)] => w == "\\",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Replaces `call` and `func` by primitives that run `pipeline` on their
+/// quotation first. Both share a [`CompileCache`].
+fn with_compiling_primitives(
+    dictionary: &BTreeMap<String, Rc<Funct>>,
+    pipeline: &Pipeline,
+) -> BTreeMap<String, Rc<Funct>> {
+    let mut dict = dictionary.clone();
+    let cache = Rc::new(RefCell::new(CompileCache::new(pipeline.clone())));
+    let c = cache.clone();
+    Interpreter::insert(
+        &mut dict,
+        "call",
+        Rc::new(move |int| compiled_call(int, &c)),
+    );
+    Interpreter::insert(
+        &mut dict,
+        "func",
+        Rc::new(move |int| compiled_func(int, &cache)),
+    );
+
+    dict
+}
+
+fn compiled_call(mut int: Interpreter, cache: &RefCell<CompileCache>) -> Interpreter {
+    if let Some(StackElement::SubStack(qt)) = int.datastack.last() {
+        let qt = cache.borrow_mut().compile(qt, &int);
+        *int.datastack.last_mut().unwrap() = StackElement::SubStack(qt);
+    }
+
+    int.call()
+}
+
+fn compiled_func(mut int: Interpreter, cache: &RefCell<CompileCache>) -> Interpreter {
+    let len = int.datastack.len();
+    if let Some(StackElement::SubStack(qt)) = int.datastack.get(len.wrapping_sub(2)) {
+        let qt = cache.borrow_mut().compile(qt, &int);
+        int.datastack[len - 2] = StackElement::SubStack(qt);
+    }
+
+    int.func()
+}
+
+thread_local! {
+    static QUOTATIONS_COMPILED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// How many quotations `call` and `func` compiled on this thread, not
+/// counting those found in a cache.
+pub fn quotations_compiled() -> usize {
+    QUOTATIONS_COMPILED.get()
+}
+
+pub(crate) fn count_compiled_quotation() {
+    QUOTATIONS_COMPILED.set(QUOTATIONS_COMPILED.get() + 1);
+}
+
+/// The number of quotations a [`CompileCache`] holds before it starts over.
+const COMPILE_CACHE_SIZE: usize = 4096;
+
+/// Quotations already compiled by `call` and `func`, so loops compile their
+/// body once. The cache belongs to one dictionary and is emptied as soon as
+/// it is used with another one, like after a `set-dict`.
+struct CompileCache {
+    pipeline: Pipeline,
+    dictionary: Weak<BTreeMap<String, Rc<Funct>>>,
+    /// Compiled quotations by the hash of their source.
+    entries: HashMap<u64, Vec<CacheEntry>>,
+    len: usize,
+}
+
+struct CacheEntry {
+    source: Vec<StackElement>,
+    compiled: Vec<StackElement>,
+}
+
+impl CompileCache {
+    fn new(pipeline: Pipeline) -> Self {
+        Self {
+            pipeline,
+            dictionary: Weak::new(),
+            entries: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    fn compile(&mut self, qt: &[StackElement], int: &Interpreter) -> Vec<StackElement> {
+        if !ptr::eq(self.dictionary.as_ptr(), Rc::as_ptr(&int.dictionary)) {
+            self.clear();
+            self.dictionary = Rc::downgrade(&int.dictionary);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hash_elements(qt, &mut hasher);
+        let key = hasher.finish();
+        if let Some(entry) = self
+            .entries
+            .get(&key)
+            .and_then(|entries| entries.iter().find(|e| e.source == qt))
+        {
+            return entry.compiled.clone();
+        }
+
+        let context = Context::new(&int.dictionary, int.inline_budget).with_pragmas(&int.pragmas);
+        let compiled = self.pipeline.run("", qt, &context);
+        count_compiled_quotation();
+        if self.len == COMPILE_CACHE_SIZE {
+            self.clear();
+        }
+        self.entries.entry(key).or_default().push(CacheEntry {
+            source: qt.to_vec(),
+            compiled: compiled.clone(),
+        });
+        self.len += 1;
+
+        compiled
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.len = 0;
+    }
+}
+
+/// Hashes elements consistently with their `PartialEq`: compiled code by its
+/// source and built-ins by identity.
+pub(crate) fn hash_elements(words: &[StackElement], state: &mut impl Hasher) {
+    for se in words {
+        match se {
+            StackElement::Word(w) => (0u8, w).hash(state),
+            StackElement::SubStack(ss) => {
+                (1u8, ss.len()).hash(state);
+                hash_elements(ss, state);
+            }
+            StackElement::Map(m) => {
+                (2u8, m.len()).hash(state);
+                for (k, v) in m {
+                    hash_elements(slice::from_ref(k), state);
+                    hash_elements(slice::from_ref(v), state);
+                }
+            }
+            StackElement::Fun(f) => match f.deref() {
+                Funct::BuiltIn(_) => (3u8, Rc::as_ptr(f) as *const () as usize).hash(state),
+                Funct::SelfDefined(sd) => {
+                    4u8.hash(state);
+                    hash_elements(slice::from_ref(sd), state);
+                }
+                Funct::Compiled(src, _) => {
+                    (5u8, src.len()).hash(state);
+                    hash_elements(src, state);
+                }
+            },
+            StackElement::Nil => 6u8.hash(state),
+        }
+    }
 }
 
 fn wrap_word(word: String) -> BuiltIn {
     Rc::new(move |mut int: Interpreter| {
         match int.dictionary.clone().get(&word) {
             Some(fun) => match fun.deref() {
-                Funct::BuiltIn(fct) => fct(int),
+                Funct::BuiltIn(fct) | Funct::Compiled(_, fct) => fct(int),
                 Funct::SelfDefined(stack) => {
                     if let StackElement::SubStack(ss) = stack.to_owned() {
-                        int.callstack.append(&mut map_to_functions(&ss));
+                        int.callstack.append(&mut ss.clone());
                         int
                     } else {
                         unimplemented!()
@@ -211,15 +973,28 @@ fn wrap_word(word: String) -> BuiltIn {
             None => {
                 int.datastack.push(StackElement::Word(word.clone()));
                 int.callstack
-                    .push(StackElement::Fun(Rc::new(Funct::BuiltIn(wrap_word(
-                        "read-word".to_string(),
-                    )))));
+                    .push(StackElement::Word("read-word".to_string()));
                 int
             }
         }
     })
 }
 
+fn compiled(word: &str, code: BuiltIn) -> StackElement {
+    StackElement::Fun(Rc::new(Funct::Compiled(
+        vec![StackElement::Word(word.to_string())],
+        code,
+    )))
+}
+
+/// The escaped element `\ se`, pushed straight onto the datastack.
+pub fn escape(se: StackElement) -> StackElement {
+    StackElement::Fun(Rc::new(Funct::Compiled(
+        vec![se.clone(), StackElement::Word("\\".to_string())],
+        pull_to_ds(se),
+    )))
+}
+
 fn pull_to_ds(se: StackElement) -> BuiltIn {
     Rc::new(move |mut int: Interpreter| {
         int.datastack.push(se.clone());
@@ -227,77 +1002,38 @@ fn pull_to_ds(se: StackElement) -> BuiltIn {
     })
 }
 
+fn push_to_cs(ops: Vec<StackElement>) -> BuiltIn {
+    Rc::new(move |mut int: Interpreter| {
+        int.callstack.extend(ops.iter().cloned());
+        int
+    })
+}
+
+/// Runs the functions one after the other in a loop, so a composition needs
+/// the same native stack however long it is.
 fn compose_functions(words: &[StackElement]) -> BuiltIn {
-    if words.is_empty() {
-        return Rc::new(move |int: Interpreter| int);
-    }
-    words
+    let code: Vec<BuiltIn> = words
         .iter()
+        .rev()
         .map(|se| match se {
             StackElement::Fun(f) => match f.deref() {
-                Funct::BuiltIn(bi) => bi.clone(),
+                Funct::BuiltIn(bi) | Funct::Compiled(_, bi) => bi.clone(),
                 Funct::SelfDefined(_) => {
                     panic!("auch SelfDefined sollte es hier nicht mehr geben")
                 }
             },
-            StackElement::SubStack(ss) => {
-                pull_to_ds(StackElement::SubStack(vec![StackElement::Fun(Rc::new(
-                    Funct::BuiltIn(compose_functions(ss)),
-                ))]))
-            }
-            StackElement::Map(m) => pull_to_ds(StackElement::Map(
-                m.iter()
-                    .map(|(k, v)| {
-                        (
-                            k.clone(),
-                            match v {
-                                StackElement::SubStack(ss) => StackElement::Fun(Rc::new(
-                                    Funct::BuiltIn(compose_functions(ss.as_slice())),
-                                )),
-                                _ => unimplemented!(),
-                            },
-                        )
-                    })
-                    .collect(),
-            )),
             _ => panic!("gibts hier nicht"),
         })
-        .reduce(|a, b| compose_two(a, b))
-        .unwrap()
-}
-
-fn compose_two(a: BuiltIn, b: BuiltIn) -> BuiltIn {
-    Rc::new(move |i| a(b(i)))
-}
-
-pub fn call_fn_step_1(
-    word: String,
-    words: &[StackElement],
-    dictionary: &Rc<BTreeMap<String, Rc<Funct>>>,
-) -> Vec<StackElement> {
-    preprocess(word, words, dictionary)
-}
-
-pub fn call_fn_step_2(
-    word: String,
-    words: &[StackElement],
-    dictionary: &Rc<BTreeMap<String, Rc<Funct>>>,
-) -> Vec<StackElement> {
-    replace_with_fun(&call_fn_step_1(word, words, dictionary), dictionary)
-}
-
-pub fn call_fn_step_3(
-    word: String,
-    words: &[StackElement],
-    dictionary: &Rc<BTreeMap<String, Rc<Funct>>>,
-) -> Vec<StackElement> {
-    map_to_functions(&call_fn_step_2(word, words, dictionary))
-}
+        .collect();
 
-pub fn call_fn_step_4(
-    word: String,
-    words: &[StackElement],
-    dictionary: &Rc<BTreeMap<String, Rc<Funct>>>,
-) -> BuiltIn {
-    compose_functions(&call_fn_step_3(word.clone(), words, dictionary))
+    Rc::new(move |mut int: Interpreter| {
+        for (i, f) in code.iter().enumerate() {
+            // The first function runs in the step that started the composition.
+            if i > 0 {
+                int = int.step();
+            }
+            int = f(int);
+        }
+        int
+    })
 }
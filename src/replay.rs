@@ -0,0 +1,264 @@
+use std::{
+    cell::{Ref, RefCell},
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    error::{raise, ConsizeError},
+    io::Io,
+};
+
+/// One recorded result of an IO primitive. In the log file, an entry is a
+/// header line `<primitive> <ok|err> <length>` followed by `<length>` bytes of
+/// payload and a newline, so the log stays readable and can be edited by hand.
+/// The file primitives append the length of the path they accessed to the
+/// header line and put the path, followed by a newline, before the payload.
+/// The payload of `spit` and `spit-on` is the data they wrote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    primitive: String,
+    path: Option<String>,
+    result: Result<String, String>,
+}
+
+/// Passes everything on to `inner` and logs every result read from the
+/// outside world, and the data written to files, to a file or any other
+/// writer, which [`ReplayIo`] can feed back later.
+pub struct RecordingIo<W: Write = File> {
+    inner: Rc<dyn Io>,
+    log: RefCell<W>,
+}
+
+impl RecordingIo {
+    pub fn create(inner: Rc<dyn Io>, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(inner, File::create(path)?))
+    }
+}
+
+impl<W: Write> RecordingIo<W> {
+    pub fn new(inner: Rc<dyn Io>, log: W) -> Self {
+        Self {
+            inner,
+            log: RefCell::new(log),
+        }
+    }
+
+    /// The writer the log goes to.
+    pub fn log(&self) -> Ref<'_, W> {
+        self.log.borrow()
+    }
+
+    fn record<T>(
+        &self,
+        primitive: &str,
+        path: Option<&Path>,
+        result: io::Result<T>,
+        payload: &str,
+    ) -> io::Result<T> {
+        let (status, payload) = match &result {
+            Ok(_) => ("ok", payload.to_string()),
+            Err(err) => ("err", err.to_string()),
+        };
+        let header = match path.map(|path| path.display().to_string()) {
+            Some(path) => format!(
+                "{primitive} {status} {} {}\n{path}",
+                payload.len(),
+                path.len()
+            ),
+            None => format!("{primitive} {status} {}", payload.len()),
+        };
+        // The log is written right away so it survives a crashing session.
+        let mut log = self.log.borrow_mut();
+        if let Err(err) = write!(log, "{header}\n{payload}\n").and_then(|_| log.flush()) {
+            raise(ConsizeError::Record(err.to_string()));
+        }
+
+        result
+    }
+}
+
+impl<W: Write> Io for RecordingIo<W> {
+    fn print(&self, text: &str) {
+        self.inner.print(text)
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+
+    fn read_line(&self) -> String {
+        let line = self.inner.read_line();
+        self.record("read-line", None, Ok(()), &line).unwrap();
+        line
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        let content = self.inner.read_file(path);
+        let payload = content.as_deref().unwrap_or_default().to_string();
+        self.record("slurp", Some(path), content, &payload)
+    }
+
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.record("spit", Some(path), self.inner.write_file(path, data), data)
+    }
+
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        let result = self.inner.append_file(path, data);
+        self.record("spit-on", Some(path), result, data)
+    }
+
+    fn current_time_millis(&self) -> u128 {
+        let millis = self.inner.current_time_millis();
+        self.record("current-time-millis", None, Ok(()), &millis.to_string())
+            .unwrap();
+        millis
+    }
+}
+
+/// Serves the results logged by [`RecordingIo`] instead of reading stdin or
+/// touching the file system. Output still goes to `inner`.
+pub struct ReplayIo {
+    inner: Rc<dyn Io>,
+    entries: RefCell<VecDeque<Entry>>,
+}
+
+impl ReplayIo {
+    pub fn open(inner: Rc<dyn Io>, path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(inner, &fs::read_to_string(path)?)
+    }
+
+    /// Replays the entries of `log`, as [`RecordingIo`] wrote it.
+    pub fn new(inner: Rc<dyn Io>, log: &str) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            entries: RefCell::new(parse(log)?),
+        })
+    }
+
+    fn next(&self, primitive: &str, path: Option<&Path>) -> io::Result<String> {
+        let entry = match self.entries.borrow_mut().pop_front() {
+            Some(entry) => entry,
+            None => raise(ConsizeError::Replay(format!(
+                "{primitive} was called after the recording ended"
+            ))),
+        };
+        if entry.primitive != primitive {
+            raise(ConsizeError::Replay(format!(
+                "{primitive} was called where {} was recorded",
+                entry.primitive
+            )));
+        }
+        let path = path.map(|path| path.display().to_string());
+        if entry.path != path {
+            raise(ConsizeError::Replay(format!(
+                "{primitive} accessed {} where {} was recorded",
+                path.unwrap_or_default(),
+                entry.path.unwrap_or_default()
+            )));
+        }
+
+        entry.result.map_err(io::Error::other)
+    }
+
+    /// Like [`next`](Self::next) for primitives that write `data` to a file.
+    fn write(&self, primitive: &str, path: &Path, data: &str) -> io::Result<()> {
+        match self.next(primitive, Some(path)) {
+            Ok(recorded) if recorded != data => raise(ConsizeError::Replay(format!(
+                "{primitive} wrote other data to {} than recorded",
+                path.display()
+            ))),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+impl Io for ReplayIo {
+    fn print(&self, text: &str) {
+        self.inner.print(text)
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+
+    fn read_line(&self) -> String {
+        self.next("read-line", None).unwrap()
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.next("slurp", Some(path))
+    }
+
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.write("spit", path, data)
+    }
+
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.write("spit-on", path, data)
+    }
+
+    fn current_time_millis(&self) -> u128 {
+        let millis = self.next("current-time-millis", None).unwrap();
+        millis.parse().unwrap_or_else(|_| {
+            raise(ConsizeError::Replay(format!(
+                "current-time-millis was recorded as {millis}"
+            )))
+        })
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "invalid replay log")
+}
+
+/// Takes a field of `len` bytes and the newline after it from `rest`.
+fn take(rest: &mut &str, len: &str) -> io::Result<String> {
+    let len: usize = len.parse().map_err(|_| invalid())?;
+    let field = rest.get(..len).ok_or_else(invalid)?.to_string();
+    *rest = rest
+        .get(len..)
+        .and_then(|t| t.strip_prefix('\n'))
+        .ok_or_else(invalid)?;
+    Ok(field)
+}
+
+fn parse(log: &str) -> io::Result<VecDeque<Entry>> {
+    let mut entries = VecDeque::new();
+    let mut rest = log;
+
+    while !rest.is_empty() {
+        let (header, tail) = rest.split_once('\n').ok_or_else(invalid)?;
+        let mut fields = header.split(' ');
+        let (Some(primitive), Some(status), Some(len), path_len, None) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(invalid());
+        };
+        if path_len.is_some() != matches!(primitive, "slurp" | "spit" | "spit-on") {
+            return Err(invalid());
+        }
+        rest = tail;
+        let path = path_len.map(|len| take(&mut rest, len)).transpose()?;
+        let payload = take(&mut rest, len)?;
+
+        entries.push_back(Entry {
+            primitive: primitive.to_string(),
+            path,
+            result: match status {
+                "ok" => Ok(payload),
+                "err" => Err(payload),
+                _ => return Err(invalid()),
+            },
+        });
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,350 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    interpreter::Interpreter,
+    io::OverlayIo,
+    pass::{Context, Pipeline},
+    preprocessor::{dependents, optimise_dict, reoptimise},
+    stack_element::{Funct, StackElement},
+};
+
+/// An interpreter with nothing but the primitives in its dictionary.
+pub fn primitives() -> Interpreter {
+    Interpreter::new(
+        Vec::new(),
+        Vec::new(),
+        Rc::new(Interpreter::init_dictionary()),
+    )
+}
+
+/// The prelude and the bootimage it boots from as they were when the
+/// library was built.
+pub const PRELUDE: &str = include_str!("../prelude-plain.txt");
+pub const BOOTIMAGE: &str = include_str!("../bootimage.txt");
+
+/// Where the dictionary beyond the primitives comes from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Prelude {
+    /// [`PRELUDE`], booting from [`BOOTIMAGE`].
+    #[default]
+    Embedded,
+    /// A prelude file. The `bootimage.txt` it runs is the one next to it, or
+    /// [`BOOTIMAGE`] if there is none.
+    File(PathBuf),
+    /// Nothing but the primitives.
+    None,
+}
+
+/// Loads the prelude built into the library into the dictionary.
+pub fn load_prelude(int: Interpreter) -> Interpreter {
+    load(int, &Prelude::Embedded).unwrap()
+}
+
+/// Loads `prelude` into the dictionary. Only a prelude file can fail to be
+/// read.
+pub fn load(mut int: Interpreter, prelude: &Prelude) -> io::Result<Interpreter> {
+    let io = int.io.clone();
+    let overlay = OverlayIo::new(io.clone());
+    let (overlay, source) = match prelude {
+        Prelude::Embedded => (
+            overlay.with_file("bootimage.txt", BOOTIMAGE),
+            PRELUDE.into(),
+        ),
+        Prelude::File(path) => {
+            let bootimage = io
+                .read_file(&path.with_file_name("bootimage.txt"))
+                .map_or(Cow::Borrowed(BOOTIMAGE), Cow::Owned);
+            (
+                overlay.with_file("bootimage.txt", bootimage),
+                read(&int, path)?,
+            )
+        }
+        Prelude::None => return Ok(int),
+    };
+    int.io = Rc::new(overlay);
+    let mut int = run(int, &source, 0);
+    int.io = io;
+    Ok(int)
+}
+
+/// Runs the file at `path` at level 0, like `run` does, to add its
+/// definitions to the dictionary.
+pub fn load_file(int: Interpreter, path: &Path) -> io::Result<Interpreter> {
+    let source = read(&int, path)?;
+    Ok(run(int, &source, 0))
+}
+
+fn read(int: &Interpreter, path: &Path) -> io::Result<String> {
+    int.io
+        .read_file(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("cannot read {}: {err}", path.display())))
+}
+
+/// Runs the bootimage in `file` on the primitives in `int`. Afterwards the
+/// dictionary holds `:`, the bracket parser and everything else needed to
+/// read the prelude, and nothing more.
+pub fn boot(int: Interpreter, file: &str) -> Interpreter {
+    run(int, &format!("\\ {file} run"), 0)
+}
+
+/// Writes the words the prelude's `bootstrapping-dict` selects to `file`
+/// with the prelude's `dump`, as a bootimage for [`boot`]. `int` has to hold
+/// the prelude.
+pub fn dump_bootimage(int: Interpreter, file: &str) -> Interpreter {
+    run(int, &format!("bootstrapping-dict \\ {file} dump"), 0)
+}
+
+/// Defines the word `args` to push `args` as a stack of words, the first
+/// one on top.
+pub fn with_args(int: Interpreter, args: &[String]) -> Interpreter {
+    let args = args.iter().rev().cloned().map(StackElement::Word).collect();
+    let mut dictionary = (*int.dictionary).clone();
+    dictionary.insert(
+        "args".to_string(),
+        Rc::new(Funct::SelfDefined(StackElement::SubStack(vec![
+            StackElement::SubStack(args),
+        ]))),
+    );
+    reoptimise(int, dictionary)
+}
+
+/// Prepares the dictionary for running code at `level`.
+pub fn optimise(int: Interpreter, level: u8) -> Interpreter {
+    optimise_with(int, &Pipeline::level(level))
+}
+
+/// Prepares the dictionary for running code transformed by `pipeline`.
+pub fn optimise_with(int: Interpreter, pipeline: &Pipeline) -> Interpreter {
+    match pipeline.is_empty() {
+        true => int,
+        false => optimise_dict(int, pipeline),
+    }
+}
+
+/// Runs `code` at `level` on an empty datastack. The resulting datastack is
+/// left on top of the datastack as a single stack. `level` has to be at most
+/// [`MAX_LEVEL`](crate::pass::MAX_LEVEL).
+pub fn run(int: Interpreter, code: &str, level: u8) -> Interpreter {
+    run_with(int, code, &Pipeline::level(level))
+}
+
+/// Like [`run`], but transforms `code` by `pipeline`.
+pub fn run_with(mut int: Interpreter, code: &str, pipeline: &Pipeline) -> Interpreter {
+    int.datastack = vec![StackElement::Word(code.to_string())];
+    call_with(int, pipeline)
+}
+
+/// Parses the source on top of the datastack, transforms it for `level` and
+/// applies it to an empty stack.
+pub fn call(int: Interpreter, level: u8) -> Interpreter {
+    call_with(int, &Pipeline::level(level))
+}
+
+/// Like [`call`], but transforms the source by `pipeline`.
+pub fn call_with(int: Interpreter, pipeline: &Pipeline) -> Interpreter {
+    apply_top(transform(int, pipeline))
+}
+
+/// Applies `program`, already transformed, to an empty stack like [`call`].
+pub fn apply(mut int: Interpreter, program: Vec<StackElement>) -> Interpreter {
+    int.datastack.push(StackElement::SubStack(program));
+    apply_top(int)
+}
+
+/// Like `get-dict func`, but the dictionary does not count against
+/// [`Limits::max_elements`](crate::limits::Limits::max_elements).
+fn func(mut int: Interpreter) -> Interpreter {
+    int.datastack.push(int.dictionary_map());
+    int.func()
+}
+
+fn apply_top(int: Interpreter) -> Interpreter {
+    let mut int1 = func(int);
+    int1.datastack.push(StackElement::SubStack(Vec::new()));
+    int1.swap().apply()
+}
+
+/// Replaces the source on top of the datastack by the quotation `pipeline`
+/// makes of it.
+pub fn transform(int: Interpreter, pipeline: &Pipeline) -> Interpreter {
+    let mut int1 = int.uncomment().tokenize();
+    let program = match int1.datastack.pop().unwrap() {
+        StackElement::SubStack(ss) => ss,
+        _ => panic!("passiert nicht"),
+    };
+    let mut parts = split_definitions(program);
+    if !pipeline.is_empty() {
+        for (_, code) in parts.iter_mut().filter(|(definition, _)| !definition) {
+            int1 = parse(int1, std::mem::take(code));
+            *code = match int1.datastack.pop().unwrap() {
+                StackElement::SubStack(ss) => ss,
+                _ => panic!("passiert nicht"),
+            };
+        }
+    }
+    let dictionary = without_redefined(&int1, &parts);
+    let context = Context::new(&dictionary, int1.inline_budget).with_pragmas(&int1.pragmas);
+    let transform = |code: Vec<StackElement>| pipeline.run("", &code, &context);
+    let new_program = parts
+        .into_iter()
+        .flat_map(|(definition, code)| if definition { code } else { transform(code) })
+        .collect();
+
+    int1.datastack.push(StackElement::SubStack(new_program));
+    int1
+}
+
+/// The dictionary of `int` without the words the definitions among `parts`
+/// define and the words using them. The definitions only run after the code
+/// is transformed, so the code has to call these words instead of inlining
+/// or resolving what they meant before.
+fn without_redefined(
+    int: &Interpreter,
+    parts: &[(bool, Vec<StackElement>)],
+) -> Rc<BTreeMap<String, Rc<Funct>>> {
+    let defined: BTreeSet<String> = parts
+        .iter()
+        .filter(|(definition, _)| *definition)
+        .filter_map(|(_, code)| match code.iter().rev().nth(1) {
+            Some(StackElement::Word(name)) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    if defined.is_empty() {
+        return int.dictionary.clone();
+    }
+    // Optimised definitions may hold an inlined copy of a word instead of a
+    // call, so users are looked up in the definitions as written.
+    let source = match &int.optimisation {
+        Some(optimisation) => &optimisation.source,
+        None => &int.dictionary,
+    };
+    let stale = dependents(source, defined);
+    Rc::new(
+        int.dictionary
+            .iter()
+            .filter(|(name, _)| !stale.contains(*name))
+            .map(|(name, f)| (name.clone(), f.clone()))
+            .collect(),
+    )
+}
+
+/// Splits a tokenized program in callstack order into the `: name ... ;`
+/// definitions outside of brackets and the code in between. `:` reads a
+/// definition from the callstack, so definitions have to reach it as written,
+/// stack effect included.
+fn split_definitions(program: Vec<StackElement>) -> Vec<(bool, Vec<StackElement>)> {
+    let mut parts: Vec<(bool, Vec<StackElement>)> = Vec::new();
+    let mut definition = false;
+    let mut depth = 0usize;
+    let mut escaped = false;
+    for se in program.into_iter().rev() {
+        let word = match &se {
+            StackElement::Word(w) if !escaped => Some(w.as_str()),
+            _ => None,
+        };
+        escaped = word == Some("\\");
+        if !definition {
+            match word {
+                Some("[" | "(" | "{") => depth += 1,
+                Some("]" | ")" | "}") => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        if word == Some(":") && !definition && depth == 0 {
+            definition = true;
+            parts.push((true, Vec::new()));
+        } else if parts.last().is_none_or(|(d, _)| *d != definition) {
+            parts.push((definition, Vec::new()));
+        }
+        let closes = definition && word == Some(";");
+        parts.last_mut().unwrap().1.push(se);
+        if closes {
+            definition = false;
+        }
+    }
+    for (_, code) in &mut parts {
+        code.reverse();
+    }
+    parts.reverse();
+    parts
+}
+
+/// Pushes `code` with its brackets turned into nested stacks, so the
+/// preprocessor does not mistake them for words. At level 0 the prelude's `[`
+/// does the same while the program runs.
+fn parse(mut int: Interpreter, code: Vec<StackElement>) -> Interpreter {
+    if !int.dictionary.contains_key("parse-quot") {
+        int.datastack.push(StackElement::SubStack(code));
+        return int;
+    }
+    int.datastack
+        .push(StackElement::SubStack(vec![StackElement::SubStack(
+            code.clone(),
+        )]));
+    int.datastack
+        .push(StackElement::SubStack(vec![StackElement::Word(
+            "parse-quot".to_string(),
+        )]));
+    let mut int = func(int).apply();
+    match int.datastack.pop() {
+        Some(StackElement::SubStack(result)) => {
+            let parsed = match result.as_slice() {
+                [StackElement::SubStack(parsed)] => parsed.clone(),
+                _ => panic!("passiert nicht"),
+            };
+            let parsed = escape_literals(&code, parsed);
+            int.datastack.push(StackElement::SubStack(parsed));
+            int
+        }
+        _ => panic!("passiert nicht"),
+    }
+}
+
+/// Escapes the stacks and mappings `(` and `{` made of `tokens` in
+/// `parsed`, both in callstack order, so the passes take them for the data
+/// they are, not for quotations. Quotations are left as written, since an
+/// escape in them would be part of the data.
+fn escape_literals(tokens: &[StackElement], parsed: Vec<StackElement>) -> Vec<StackElement> {
+    let mut tokens = tokens.iter().rev().map(|se| match se {
+        StackElement::Word(w) => w.as_str(),
+        _ => "",
+    });
+    let mut escaped = Vec::new();
+    let mut parsed = parsed.into_iter().rev();
+    while let Some(se) = parsed.next() {
+        let token = tokens.next();
+        if token == Some("\\") {
+            escaped.push(se);
+            tokens.next();
+            escaped.extend(parsed.next());
+            continue;
+        }
+        if matches!(token, Some("(" | "{")) {
+            escaped.push(StackElement::Word("\\".to_string()));
+        }
+        escaped.push(se);
+        if matches!(token, Some("[" | "(" | "{")) {
+            let mut depth = 1;
+            while depth > 0 {
+                match tokens.next() {
+                    Some("[" | "(" | "{") => depth += 1,
+                    Some("]" | ")" | "}") => depth -= 1,
+                    Some("\\") => {
+                        tokens.next();
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+    }
+    escaped.reverse();
+    escaped
+}
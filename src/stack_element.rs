@@ -23,6 +23,10 @@ pub enum StackElement {
 pub enum Funct {
     BuiltIn(BuiltIn),
     SelfDefined(StackElement),
+    /// Code produced by the preprocessor together with the callstack elements
+    /// it replaces. Running the code has the same effect as putting the source
+    /// onto the callstack, so the source can always be restored with [`reify`].
+    Compiled(Vec<StackElement>, BuiltIn),
 }
 
 impl Debug for Funct {
@@ -30,6 +34,7 @@ impl Debug for Funct {
         match self {
             Self::BuiltIn(_arg0) => f.debug_tuple("BuiltIn").finish(),
             Self::SelfDefined(arg0) => f.debug_tuple("SelfDefined").field(arg0).finish(),
+            Self::Compiled(arg0, _arg1) => f.debug_tuple("Compiled").field(arg0).finish(),
         }
     }
 }
@@ -44,6 +49,15 @@ impl Display for StackElement {
             Self::Fun(fct) => match fct.deref() {
                 Funct::BuiltIn(bi) => write!(f, "{:p}", bi),
                 Funct::SelfDefined(bi) => write!(f, "{}", bi),
+                Funct::Compiled(src, _) => write!(
+                    f,
+                    "{}",
+                    src.iter()
+                        .rev()
+                        .map(|e| e.to_string())
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                ),
             },
         }
     }
@@ -79,11 +93,15 @@ impl PartialEq for StackElement {
                 Self::Fun(fk) => match f.deref() {
                     Funct::BuiltIn(bi) => match fk.deref() {
                         Funct::BuiltIn(bik) => std::ptr::eq(bi, bik),
-                        Funct::SelfDefined(_) => false,
+                        _ => false,
                     },
                     Funct::SelfDefined(sd) => match fk.deref() {
-                        Funct::BuiltIn(_) => false,
                         Funct::SelfDefined(sdk) => sd == sdk,
+                        _ => false,
+                    },
+                    Funct::Compiled(src, _) => match fk.deref() {
+                        Funct::Compiled(srck, _) => src == srck,
+                        _ => false,
                     },
                 },
                 _ => false,
@@ -139,3 +157,87 @@ pub fn print_stack(stack: &Vec<StackElement>, print_brackets: bool, reverse: boo
 
     str
 }
+
+/// Prints `stack` in reading order, showing the functions the preprocessor
+/// put in place of source code: `<dup>` is the Rust function for `dup` and a
+/// composition shows all the source it replaces, like `<1 2 + dup>`.
+/// Built-ins without source are named after their entry in `dictionary`.
+pub fn print_ir(stack: &[StackElement], dictionary: &BTreeMap<String, Rc<Funct>>) -> String {
+    stack
+        .iter()
+        .rev()
+        .map(|e| match e {
+            StackElement::SubStack(ss) if ss.is_empty() => "[ ]".to_string(),
+            StackElement::SubStack(ss) => format!("[ {} ]", print_ir(ss, dictionary)),
+            StackElement::Map(m) => {
+                let entries: Vec<String> = m
+                    .iter()
+                    .map(|(k, v)| {
+                        let (k, v) = (std::slice::from_ref(k), std::slice::from_ref(v));
+                        format!("{}, {} ", print_ir(k, dictionary), print_ir(v, dictionary))
+                    })
+                    .collect();
+                format!("{{ {}}}", entries.concat())
+            }
+            StackElement::Fun(f) => match f.deref() {
+                Funct::BuiltIn(_) => {
+                    let name = dictionary
+                        .iter()
+                        .find(|(_, g)| Rc::ptr_eq(f, g))
+                        .map_or("builtin", |(n, _)| n.as_str());
+                    format!("<{name}>")
+                }
+                Funct::SelfDefined(sd) => print_ir(std::slice::from_ref(sd), dictionary),
+                Funct::Compiled(src, _) => format!("<{}>", print_ir(src, dictionary)),
+            },
+            _ => e.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Replaces all compiled code by its source, turning a callstack into plain
+/// data that can be inspected and manipulated by Consize programs.
+pub fn reify(stack: &[StackElement]) -> Vec<StackElement> {
+    stack
+        .iter()
+        .flat_map(|e| match e {
+            StackElement::Fun(f) => match f.deref() {
+                Funct::Compiled(src, _) => reify(src),
+                _ => vec![e.to_owned()],
+            },
+            _ => vec![reify_element(e)],
+        })
+        .collect()
+}
+
+fn reify_element(e: &StackElement) -> StackElement {
+    match e {
+        StackElement::SubStack(ss) => StackElement::SubStack(reify(ss)),
+        StackElement::Map(m) => StackElement::Map(
+            m.iter()
+                .map(|(k, v)| (reify_element(k), reify_element(v)))
+                .collect(),
+        ),
+        _ => e.to_owned(),
+    }
+}
+
+pub fn count_elements(stack: &[StackElement]) -> usize {
+    stack
+        .iter()
+        .map(|e| match e {
+            StackElement::SubStack(ss) => 1 + count_elements(ss),
+            StackElement::Map(m) => {
+                1 + m
+                    .iter()
+                    .map(|(k, v)| {
+                        count_elements(std::slice::from_ref(k))
+                            + count_elements(std::slice::from_ref(v))
+                    })
+                    .sum::<usize>()
+            }
+            _ => 1,
+        })
+        .sum()
+}
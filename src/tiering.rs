@@ -0,0 +1,313 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    hash::Hasher,
+    ops::Deref,
+    rc::Rc,
+    slice,
+};
+
+use crate::{
+    interpreter::Interpreter,
+    pass::{Context, Pipeline},
+    pragma::Pragmas,
+    preprocessor::{dependents, hash_elements, optimise_entry, InlineBudget},
+    stack_element::{Funct, StackElement},
+};
+
+/// Words whose continuation or dictionary a promoted definition could
+/// observe in a different shape than at level 0.
+const BARRIERS: [&str; 3] = ["call/cc", "continue", "get-dict"];
+
+/// The prelude defines `call` with `call/cc`, but only to run a quotation,
+/// which every level does alike. Callers of `call` can therefore be
+/// promoted, `call` itself cannot. Instead, the quotations it runs are.
+const TRANSPARENT: [&str; 1] = ["call"];
+
+/// Adaptive optimisation: the program starts at level 0, `stepcc` counts how
+/// often each self-defined word is called, and a word called `threshold`
+/// times is replaced by its level 2 or level 4 form. Quotations `call` runs
+/// `threshold` times are handed to it in that form from then on. Words and
+/// quotations that reach `call/cc`, `continue` or `get-dict`, directly or
+/// through other definitions, keep running at level 0, as do words
+/// annotated with `no-optimise`.
+pub struct Tiering {
+    pipeline: Pipeline,
+    threshold: u32,
+    /// Calls of self-defined words by their definition, which stops counting
+    /// at `threshold`, when the word has been promoted or got stuck.
+    calls: RefCell<HashMap<*const Funct, (Rc<Funct>, u32)>>,
+    /// The promoted words with the definition they replaced.
+    words: RefCell<HashMap<String, Promotion>>,
+    /// Quotations run by `call`, by the hash of their source.
+    quotations: RefCell<HashMap<u64, Vec<Quotation>>>,
+}
+
+struct Promotion {
+    promoted: Rc<Funct>,
+    original: Rc<Funct>,
+}
+
+struct Quotation {
+    source: Vec<StackElement>,
+    tier: Tier,
+}
+
+enum Tier {
+    Counting(u32),
+    Promoted(Vec<StackElement>),
+    /// Not worth looking at again until the dictionary changes.
+    Stuck,
+}
+
+impl Tiering {
+    /// Promotes words to `level`, which has to be 2 or 4, after `threshold`
+    /// calls.
+    pub fn new(level: u8, threshold: u32) -> Self {
+        assert!(
+            level == 2 || level == 4,
+            "words can only be promoted to level 2 or 4"
+        );
+        Self {
+            pipeline: Pipeline::level(level),
+            threshold,
+            calls: RefCell::new(HashMap::new()),
+            words: RefCell::new(HashMap::new()),
+            quotations: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The words promoted so far.
+    pub fn promoted(&self) -> BTreeSet<String> {
+        self.words.borrow().keys().cloned().collect()
+    }
+
+    /// How many quotations run by `call` have been promoted.
+    pub fn promoted_quotations(&self) -> usize {
+        self.quotations
+            .borrow()
+            .values()
+            .flatten()
+            .filter(|q| matches!(q.tier, Tier::Promoted(_)))
+            .count()
+    }
+
+    /// Counts a call of the self-defined `word`, defined as `f`, and promotes
+    /// it once it is hot. A call of `call` counts its quotation instead.
+    pub(crate) fn count(&self, int: Interpreter, word: &str, f: &Rc<Funct>) -> Interpreter {
+        if TRANSPARENT.contains(&word) {
+            return self.count_quotation(int);
+        }
+        {
+            let mut calls = self.calls.borrow_mut();
+            let (_, calls) = calls.entry(Rc::as_ptr(f)).or_insert((f.clone(), 0));
+            if *calls >= self.threshold {
+                return int;
+            }
+            *calls += 1;
+            if *calls < self.threshold {
+                return int;
+            }
+        }
+        self.decide(int, word, f)
+    }
+
+    fn decide(&self, mut int: Interpreter, word: &str, f: &Rc<Funct>) -> Interpreter {
+        let no_optimise = int.pragmas.get(word).is_some_and(|p| p.no_optimise);
+        let Funct::SelfDefined(body @ StackElement::SubStack(_)) = f.deref() else {
+            return int;
+        };
+        let mut words = self.words.borrow_mut();
+        let source = source(&int.dictionary, &words);
+        if no_optimise || reaches_barrier(slice::from_ref(body), &source) {
+            return int;
+        }
+
+        let promoted = self.promote(word, f, &source, &int);
+        let mut dictionary = (*int.dictionary).clone();
+        dictionary.insert(word.to_string(), promoted.clone());
+        int.dictionary = Rc::new(dictionary);
+        // The promoted definition is self-defined at level 2, but is done.
+        let done = (promoted.clone(), self.threshold);
+        self.calls.borrow_mut().insert(Rc::as_ptr(&promoted), done);
+        let original = f.clone();
+        words.insert(word.to_string(), Promotion { promoted, original });
+        int
+    }
+
+    /// Counts the quotation on top of the datastack and replaces it by its
+    /// promoted form once it is hot.
+    pub(crate) fn count_quotation(&self, mut int: Interpreter) -> Interpreter {
+        let Some(StackElement::SubStack(qt)) = int.datastack.last_mut() else {
+            return int;
+        };
+        let mut hasher = DefaultHasher::new();
+        hash_elements(qt, &mut hasher);
+        let mut quotations = self.quotations.borrow_mut();
+        let entries = quotations.entry(hasher.finish()).or_default();
+        let entry = match entries.iter_mut().position(|e| e.source == *qt) {
+            Some(i) => &mut entries[i],
+            None => {
+                entries.push(Quotation {
+                    source: qt.clone(),
+                    tier: Tier::Counting(0),
+                });
+                entries.last_mut().unwrap()
+            }
+        };
+        if let Tier::Counting(calls) = &mut entry.tier {
+            *calls += 1;
+            if *calls < self.threshold {
+                return int;
+            }
+            let source = source(&int.dictionary, &self.words.borrow());
+            entry.tier = match reaches_barrier(qt, &source) {
+                true => Tier::Stuck,
+                false => Tier::Promoted(self.promote_quotation(
+                    qt,
+                    &source,
+                    int.inline_budget,
+                    &int.pragmas,
+                )),
+            };
+        }
+        if let Tier::Promoted(promoted) = &entry.tier {
+            *qt = promoted.clone();
+        }
+        int
+    }
+
+    /// The definition of `word` at the level of the pipeline.
+    fn promote(
+        &self,
+        word: &str,
+        f: &Rc<Funct>,
+        source: &BTreeMap<String, Rc<Funct>>,
+        int: &Interpreter,
+    ) -> Rc<Funct> {
+        let source = with_counting_call(source);
+        let context = Context::new(&source, int.inline_budget).with_pragmas(&int.pragmas);
+        optimise_entry(word, f, &context, &self.pipeline)
+    }
+
+    /// `qt` at the level of the pipeline.
+    fn promote_quotation(
+        &self,
+        qt: &[StackElement],
+        source: &BTreeMap<String, Rc<Funct>>,
+        budget: InlineBudget,
+        pragmas: &BTreeMap<String, Pragmas>,
+    ) -> Vec<StackElement> {
+        let source = with_counting_call(source);
+        let context = Context::new(&source, budget).with_pragmas(pragmas);
+        self.pipeline.run("", qt, &context)
+    }
+
+    /// Prepares `dictionary` from a `set-dict` to replace `current`.
+    /// Promoted words that inlined a changed word go back to their level 0
+    /// definition and start counting again, just like the changed words.
+    pub(crate) fn demote(
+        &self,
+        current: &BTreeMap<String, Rc<Funct>>,
+        mut dictionary: BTreeMap<String, Rc<Funct>>,
+    ) -> BTreeMap<String, Rc<Funct>> {
+        let mut changed: BTreeSet<String> = dictionary
+            .iter()
+            .filter(|(name, f)| !current.get(*name).is_some_and(|old| Rc::ptr_eq(old, f)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.extend(
+            current
+                .keys()
+                .filter(|name| !dictionary.contains_key(*name))
+                .cloned(),
+        );
+        if changed.is_empty() {
+            return dictionary;
+        }
+
+        let mut words = self.words.borrow_mut();
+        let mut calls = self.calls.borrow_mut();
+        for name in dependents(&source(&dictionary, &words), changed) {
+            if let Some(Promotion { promoted, original }) = words.remove(&name) {
+                if dictionary
+                    .get(&name)
+                    .is_some_and(|f| Rc::ptr_eq(f, &promoted))
+                {
+                    dictionary.insert(name.clone(), original);
+                }
+            }
+            for f in [current.get(&name), dictionary.get(&name)]
+                .into_iter()
+                .flatten()
+            {
+                calls.remove(&Rc::as_ptr(f));
+            }
+        }
+        self.quotations.borrow_mut().clear();
+        dictionary
+    }
+}
+
+/// `source` with `call` as a primitive that counts its quotation, so
+/// promoted code, which no longer runs `call` through `stepcc`, keeps handing
+/// quotations to the tiering.
+fn with_counting_call(source: &BTreeMap<String, Rc<Funct>>) -> Rc<BTreeMap<String, Rc<Funct>>> {
+    let mut dictionary = source.clone();
+    Interpreter::insert(&mut dictionary, "call", Rc::new(counting_call));
+    Rc::new(dictionary)
+}
+
+fn counting_call(int: Interpreter) -> Interpreter {
+    match int.tiering.clone() {
+        Some(tiering) => tiering.count_quotation(int).call(),
+        None => int.call(),
+    }
+}
+
+/// `dictionary` with the level 0 definitions of the promoted words.
+fn source(
+    dictionary: &BTreeMap<String, Rc<Funct>>,
+    words: &HashMap<String, Promotion>,
+) -> BTreeMap<String, Rc<Funct>> {
+    let mut source = dictionary.clone();
+    for (name, Promotion { promoted, original }) in words {
+        if dictionary
+            .get(name)
+            .is_some_and(|f| Rc::ptr_eq(f, promoted))
+        {
+            source.insert(name.clone(), original.clone());
+        }
+    }
+    source
+}
+
+/// Whether running `words` may execute one of the [`BARRIERS`].
+fn reaches_barrier(words: &[StackElement], dictionary: &BTreeMap<String, Rc<Funct>>) -> bool {
+    fn visit<'a>(
+        words: &'a [StackElement],
+        dictionary: &'a BTreeMap<String, Rc<Funct>>,
+        seen: &mut BTreeSet<&'a str>,
+    ) -> bool {
+        words.iter().any(|se| match se {
+            StackElement::Word(w) if BARRIERS.contains(&w.as_str()) => true,
+            StackElement::Word(w) if TRANSPARENT.contains(&w.as_str()) => false,
+            StackElement::Word(w) => {
+                seen.insert(w)
+                    && match dictionary.get(w).map(|f| f.deref()) {
+                        Some(Funct::SelfDefined(body)) => {
+                            visit(slice::from_ref(body), dictionary, seen)
+                        }
+                        _ => false,
+                    }
+            }
+            StackElement::SubStack(ss) => visit(ss, dictionary, seen),
+            StackElement::Map(m) => m
+                .iter()
+                .any(|(_, v)| visit(slice::from_ref(v), dictionary, seen)),
+            _ => false,
+        })
+    }
+
+    visit(words, dictionary, &mut BTreeSet::new())
+}
@@ -0,0 +1,293 @@
+use std::{collections::BTreeMap, fmt::Write as _, fs, io, ops::Deref, path::Path, rc::Rc};
+
+use crate::{
+    interpreter::Interpreter,
+    pass::Pipeline,
+    runner,
+    stack_element::{BuiltIn, Funct, StackElement},
+};
+
+/// A Rust crate that runs one Consize program as a standalone executable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Crate {
+    pub manifest: String,
+    pub main: String,
+}
+
+impl Crate {
+    /// Writes `Cargo.toml` and `src/main.rs` to `dir`.
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir.join("src"))?;
+        fs::write(dir.join("Cargo.toml"), &self.manifest)?;
+        fs::write(dir.join("src").join("main.rs"), &self.main)
+    }
+}
+
+/// Translates the dictionary of `int`, as [`optimise_dict`] left it, and
+/// `code` to a crate named after `name`. The executable builds the same
+/// dictionary from the library's primitives, defines `args` from its command
+/// line and runs the program on it, so it behaves like running `code` with
+/// the pipeline `int` was optimised for.
+/// Compiling pipelines hide their code in closures and cannot be translated.
+/// The crate depends on the library in the directory `runtime`, or on the
+/// checkout this library was built from.
+///
+/// [`optimise_dict`]: crate::preprocessor::optimise_dict
+pub fn transpile(
+    int: &Interpreter,
+    code: &str,
+    name: &str,
+    runtime: Option<&Path>,
+) -> Result<Crate, String> {
+    let pipeline = match &int.optimisation {
+        Some(optimisation) => optimisation.pipeline.clone(),
+        None => Pipeline::default(),
+    };
+    if pipeline.compiles() {
+        return Err(format!("the pipeline {pipeline} compiles to closures"));
+    }
+
+    let mut int1 = int.clone();
+    int1.datastack = vec![StackElement::Word(code.to_string())];
+    let program = match runner::transform(int1, &pipeline).datastack.pop() {
+        Some(StackElement::SubStack(program)) => program,
+        _ => return Err("the program does not parse".to_string()),
+    };
+
+    let translator = Translator {
+        dictionary: &int.dictionary,
+        primitives: Interpreter::init_dictionary(),
+    };
+    let mut main = String::new();
+    writeln!(
+        main,
+        "//! Generated by consize-interpreter --transpile from"
+    )
+    .unwrap();
+    writeln!(main, "//! {}", code.replace('\n', "\n//! ")).unwrap();
+    main.push_str(PRELUDE);
+    writeln!(main, "\nfn run() -> String {{").unwrap();
+    writeln!(main, "    let p = Interpreter::init_dictionary();").unwrap();
+    writeln!(
+        main,
+        "    let mut int = Interpreter::new(Vec::new(), Vec::new(), Rc::new(dictionary(&p)))"
+    )
+    .unwrap();
+    writeln!(
+        main,
+        "        .with_inline_budget(InlineBudget {{ max_size: {} }});",
+        int.inline_budget.max_size
+    )
+    .unwrap();
+    if int.optimisation.is_some() {
+        writeln!(main, "    int.optimisation = Some(Rc::new(Optimisation {{").unwrap();
+        writeln!(
+            main,
+            "        pipeline: Pipeline::parse({:?}).unwrap(),",
+            pipeline.to_string()
+        )
+        .unwrap();
+        writeln!(main, "        source: Rc::new(source(&p)),").unwrap();
+        writeln!(main, "    }}));").unwrap();
+    }
+    if !int.pragmas.is_empty() {
+        writeln!(main, "    int.pragmas = Rc::new(BTreeMap::from([").unwrap();
+        for (word, pragmas) in int.pragmas.iter() {
+            writeln!(main, "        ({word:?}.to_string(), {pragmas:?}),").unwrap();
+        }
+        writeln!(main, "    ]));").unwrap();
+    }
+    writeln!(
+        main,
+        "    let args: Vec<String> = std::env::args().skip(1).collect();"
+    )
+    .unwrap();
+    writeln!(main, "    let int = runner::with_args(int, &args);").unwrap();
+    writeln!(main, "    let int = runner::apply(int, program(&p));").unwrap();
+    writeln!(main, "    print_stack(&int.datastack, false, false)").unwrap();
+    writeln!(main, "}}").unwrap();
+    if let Some(optimisation) = &int.optimisation {
+        translator.dictionary(&mut main, "source", &optimisation.source)?;
+    }
+    translator.dictionary(&mut main, "dictionary", &int.dictionary)?;
+    writeln!(main, "\nfn program(p: &Dictionary) -> Vec<StackElement> {{").unwrap();
+    writeln!(main, "    {}", translator.elements(&program)?).unwrap();
+    writeln!(main, "}}").unwrap();
+
+    let runtime = runtime.unwrap_or(Path::new(env!("CARGO_MANIFEST_DIR")));
+    let dependency = format!("{{ path = {:?} }}", runtime.display().to_string());
+    Ok(Crate {
+        manifest: format!(
+            "[package]\nname = {:?}\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [dependencies]\nconsize-interpreter = {dependency}\n\n\
+             # Not part of any surrounding workspace.\n[workspace]\n",
+            package_name(name),
+        ),
+        main,
+    })
+}
+
+/// Names Cargo refuses for packages besides those starting with a digit.
+const RESERVED_NAMES: [&str; 43] = [
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+    "where", "while", "abstract", "try", "test", "std", "core", "alloc",
+];
+
+/// `name` turned into a valid package name: everything but ASCII letters,
+/// digits, `-` and `_` becomes `-`, and names Cargo would refuse get a
+/// `consize-` prefix.
+fn package_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '_' {
+            true => c,
+            false => '-',
+        })
+        .collect();
+    match name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && !RESERVED_NAMES.contains(&name.as_str())
+    {
+        true => name,
+        false => format!("consize-{name}"),
+    }
+}
+
+/// The part of every generated `main.rs` that does not depend on the
+/// program. `p` are the library's primitives, `w` makes a word and `r` a
+/// primitive resolved at level 2.
+const PRELUDE: &str = r#"
+use std::{collections::BTreeMap, process::exit, rc::Rc, thread};
+
+use consize_interpreter::{
+    error::{catch, quiet_hook},
+    interpreter::Interpreter,
+    pass::Pipeline,
+    pragma::Pragmas,
+    preprocessor::{escape, InlineBudget, Optimisation},
+    runner,
+    stack_element::{print_stack, Funct, StackElement, StackElement::*},
+};
+
+type Dictionary = BTreeMap<String, Rc<Funct>>;
+
+/// Primitives like `apply` execute Consize as nested Rust calls.
+const STACK_SIZE: usize = 1 << 30;
+
+fn main() {
+    quiet_hook();
+    let result = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| catch(run))
+        .unwrap()
+        .join()
+        .unwrap_or_else(|_| exit(101));
+    match result {
+        Ok(datastack) => println!("{datastack}"),
+        Err(err) => {
+            eprintln!("Error: {err}");
+            exit(1)
+        }
+    }
+}
+
+fn w(word: &str) -> StackElement {
+    Word(word.to_string())
+}
+
+fn r(p: &Dictionary, name: &str) -> StackElement {
+    match p[name].as_ref() {
+        Funct::BuiltIn(f) => Fun(Rc::new(Funct::Compiled(vec![w(name)], f.clone()))),
+        _ => unreachable!(),
+    }
+}
+
+fn defined(body: StackElement) -> Rc<Funct> {
+    Rc::new(Funct::SelfDefined(body))
+}
+"#;
+
+struct Translator<'a> {
+    dictionary: &'a BTreeMap<String, Rc<Funct>>,
+    /// A fresh copy of the primitives, to tell them apart by name.
+    primitives: BTreeMap<String, Rc<Funct>>,
+}
+
+impl Translator<'_> {
+    /// Appends the function `name` that builds `dictionary`.
+    fn dictionary(
+        &self,
+        main: &mut String,
+        name: &str,
+        dictionary: &BTreeMap<String, Rc<Funct>>,
+    ) -> Result<(), String> {
+        writeln!(main, "\nfn {name}(p: &Dictionary) -> Dictionary {{").unwrap();
+        writeln!(main, "    let mut d = Dictionary::new();").unwrap();
+        for (word, f) in dictionary {
+            let value = match f.deref() {
+                Funct::BuiltIn(_) if self.primitives.contains_key(word) => {
+                    format!("p[{word:?}].clone()")
+                }
+                Funct::BuiltIn(_) => return Err(format!("{word} is a function without source")),
+                Funct::SelfDefined(body) => format!("defined({})", self.element(body)?),
+                Funct::Compiled(..) => return Err(format!("{word} is compiled")),
+            };
+            writeln!(main, "    d.insert({word:?}.to_string(), {value});").unwrap();
+        }
+        writeln!(main, "    d").unwrap();
+        writeln!(main, "}}").unwrap();
+        Ok(())
+    }
+
+    fn elements(&self, elements: &[StackElement]) -> Result<String, String> {
+        let elements: Vec<String> = elements
+            .iter()
+            .map(|se| self.element(se))
+            .collect::<Result<_, _>>()?;
+        Ok(format!("vec![{}]", elements.join(", ")))
+    }
+
+    /// A Rust expression that evaluates to `se`.
+    fn element(&self, se: &StackElement) -> Result<String, String> {
+        Ok(match se {
+            StackElement::Word(w) => format!("w({w:?})"),
+            StackElement::SubStack(ss) => format!("SubStack({})", self.elements(ss)?),
+            StackElement::Map(m) => {
+                let entries: Vec<String> = m
+                    .iter()
+                    .map(|(k, v)| Ok(format!("({}, {})", self.element(k)?, self.element(v)?)))
+                    .collect::<Result<_, String>>()?;
+                format!("Map(vec![{}])", entries.join(", "))
+            }
+            StackElement::Nil => "Nil".to_string(),
+            StackElement::Fun(f) => match f.deref() {
+                Funct::BuiltIn(_) => match self.dictionary.iter().find(|(_, g)| Rc::ptr_eq(f, g)) {
+                    Some((name, _)) if self.primitives.contains_key(name) => {
+                        format!("Fun(p[{name:?}].clone())")
+                    }
+                    _ => return Err("a function without source".to_string()),
+                },
+                Funct::SelfDefined(body) => format!("Fun(defined({}))", self.element(body)?),
+                Funct::Compiled(src, f) => match src.as_slice() {
+                    [StackElement::Word(name)] if self.resolves(name, f) => {
+                        format!("r(p, {name:?})")
+                    }
+                    [se, StackElement::Word(w)] if w == "\\" => {
+                        format!("escape({})", self.element(se)?)
+                    }
+                    _ => return Err(format!("compiled code `{}`", se)),
+                },
+            },
+        })
+    }
+
+    /// Whether `f` is the primitive `name` as level 2 resolves it.
+    fn resolves(&self, name: &str, f: &BuiltIn) -> bool {
+        self.primitives.contains_key(name)
+            && match self.dictionary.get(name).map(|g| g.deref()) {
+                Some(Funct::BuiltIn(g)) => Rc::ptr_eq(f, g),
+                _ => false,
+            }
+    }
+}
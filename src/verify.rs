@@ -0,0 +1,296 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    fmt::Display,
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    rc::Rc,
+};
+
+use crate::{
+    error::ConsizeError,
+    interpreter::Interpreter,
+    io::Io,
+    pass::Pipeline,
+    replay::{RecordingIo, ReplayIo},
+    runner,
+    stack_element::{reify, StackElement},
+};
+
+/// How a run ended: the datastack it left or the error it stopped with,
+/// together with everything it printed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    pub result: Result<Vec<StackElement>, String>,
+    pub output: String,
+}
+
+/// The first observable difference between a run at level 0 and a run at an
+/// optimisation level. `None` stands for a missing line, element or error.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Divergence {
+    /// Printed output differs in the given line, counted from 1.
+    Output {
+        line: usize,
+        reference: Option<String>,
+        optimised: Option<String>,
+    },
+    Error {
+        reference: Option<String>,
+        optimised: Option<String>,
+    },
+    /// The resulting stacks differ in the given element, counted from the top.
+    Datastack {
+        index: usize,
+        reference: Option<StackElement>,
+        optimised: Option<StackElement>,
+    },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn or<T: Display>(value: &Option<T>, missing: &str) -> String {
+            value
+                .as_ref()
+                .map_or(missing.to_string(), |v| format!("`{v}`"))
+        }
+
+        match self {
+            Self::Output {
+                line,
+                reference,
+                optimised,
+            } => write!(
+                f,
+                "output line {line} is {} instead of {}",
+                or(optimised, "missing"),
+                or(reference, "missing")
+            ),
+            Self::Error {
+                reference,
+                optimised,
+            } => write!(
+                f,
+                "the error is {} instead of {}",
+                or(optimised, "none"),
+                or(reference, "none")
+            ),
+            Self::Datastack {
+                index,
+                reference,
+                optimised,
+            } => write!(
+                f,
+                "datastack element {index} from the top is {} instead of {}",
+                or(optimised, "missing"),
+                or(reference, "missing")
+            ),
+        }
+    }
+}
+
+/// Runs `code` at level 0 and at `level` and compares the outcomes. `int`
+/// has to hold the prelude without any optimisation. Only the run at level 0
+/// reads input and writes files, the run at `level` is served the same
+/// results. Returns the outcome at `level` if it matches.
+pub fn verify(int: Interpreter, code: &str, level: u8) -> Result<Outcome, Divergence> {
+    verify_with(int, code, &Pipeline::level(level))
+}
+
+/// Like [`verify`], but optimises with `pipeline` instead of a level.
+pub fn verify_with(
+    int: Interpreter,
+    code: &str,
+    pipeline: &Pipeline,
+) -> Result<Outcome, Divergence> {
+    let optimised = runner::optimise_with(int.clone(), pipeline);
+    compare_runs(&int, &optimised, code, pipeline)
+}
+
+/// Like [`verify`], but reuses a dictionary already optimised for `level`,
+/// which saves the preprocessing when checking many programs.
+pub fn verify_against(
+    int: &Interpreter,
+    optimised: &Interpreter,
+    code: &str,
+    level: u8,
+) -> Result<Outcome, Divergence> {
+    compare_runs(int, optimised, code, &Pipeline::level(level))
+}
+
+fn compare_runs(
+    int: &Interpreter,
+    optimised: &Interpreter,
+    code: &str,
+    pipeline: &Pipeline,
+) -> Result<Outcome, Divergence> {
+    // The reference run records its IO, which the optimised run replays.
+    let output = Rc::new(Output::new(int.io.clone()));
+    let recorder = Rc::new(RecordingIo::new(output.clone(), Vec::new()));
+    let reference = run_on(
+        int.clone(),
+        code,
+        &Pipeline::default(),
+        recorder.clone(),
+        &output,
+    );
+    let log = String::from_utf8_lossy(&recorder.log()).into_owned();
+    let output = Rc::new(Output::new(int.io.clone()));
+    let replayer = ReplayIo::new(output.clone(), &log).expect("the log was just recorded");
+    let optimised = run_on(
+        optimised.clone(),
+        code,
+        pipeline,
+        Rc::new(replayer),
+        &output,
+    );
+
+    match compare(&reference, &optimised) {
+        Some(divergence) => Err(divergence),
+        None => Ok(optimised),
+    }
+}
+
+/// Runs `code` at `level`, collecting printed output instead of printing it.
+pub fn run_captured(int: Interpreter, code: &str, level: u8) -> Outcome {
+    run_captured_with(int, code, &Pipeline::level(level))
+}
+
+fn run_captured_with(int: Interpreter, code: &str, pipeline: &Pipeline) -> Outcome {
+    let output = Rc::new(Output::new(int.io.clone()));
+    run_on(int, code, pipeline, output.clone(), &output)
+}
+
+/// Runs `code` with `io`, which prints to `output`.
+fn run_on(
+    int: Interpreter,
+    code: &str,
+    pipeline: &Pipeline,
+    io: Rc<dyn Io>,
+    output: &Output,
+) -> Outcome {
+    let int = int.with_io(io);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| runner::run_with(int, code, pipeline)))
+        .map(|int| reify(&int.datastack))
+        .map_err(message);
+
+    Outcome {
+        result,
+        output: output.text.take(),
+    }
+}
+
+fn compare(reference: &Outcome, optimised: &Outcome) -> Option<Divergence> {
+    let reference_lines: Vec<&str> = reference.output.split('\n').collect();
+    let optimised_lines: Vec<&str> = optimised.output.split('\n').collect();
+    let output =
+        first_difference(&reference_lines, &optimised_lines).map(|(i, r, o)| Divergence::Output {
+            line: i + 1,
+            reference: r.map(|l| l.to_string()),
+            optimised: o.map(|l| l.to_string()),
+        });
+
+    // A run that stopped early prints less, so a line missing at the end is
+    // reported after a differing error.
+    if let Some(Divergence::Output {
+        reference: Some(_),
+        optimised: Some(_),
+        ..
+    }) = output
+    {
+        return output;
+    }
+    match (&reference.result, &optimised.result) {
+        (Ok(r), Ok(o)) => output.or_else(|| {
+            let (r, o) = (top_first(r), top_first(o));
+            first_difference(&r, &o).map(|(index, r, o)| Divergence::Datastack {
+                index,
+                reference: r.map(|e| (*e).clone()),
+                optimised: o.map(|e| (*e).clone()),
+            })
+        }),
+        (Err(r), Err(o)) if r == o => output,
+        (r, o) => Some(Divergence::Error {
+            reference: r.as_ref().err().cloned(),
+            optimised: o.as_ref().err().cloned(),
+        }),
+    }
+}
+
+/// Runs leave their result as a single stack on top of the datastack.
+fn result_stack(datastack: &[StackElement]) -> &[StackElement] {
+    match datastack.last() {
+        Some(StackElement::SubStack(result)) => result,
+        _ => datastack,
+    }
+}
+
+fn top_first(datastack: &[StackElement]) -> Vec<&StackElement> {
+    result_stack(datastack).iter().rev().collect()
+}
+
+fn first_difference<'a, T: PartialEq>(
+    a: &'a [T],
+    b: &'a [T],
+) -> Option<(usize, Option<&'a T>, Option<&'a T>)> {
+    (0..a.len().max(b.len()))
+        .map(|i| (i, a.get(i), b.get(i)))
+        .find(|(_, x, y)| x != y)
+}
+
+fn message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(err) = payload.downcast_ref::<ConsizeError>() {
+        err.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+/// Keeps printed output for comparison and passes everything else on to
+/// `inner`.
+struct Output {
+    inner: Rc<dyn Io>,
+    text: RefCell<String>,
+}
+
+impl Output {
+    fn new(inner: Rc<dyn Io>) -> Self {
+        Self {
+            inner,
+            text: RefCell::new(String::new()),
+        }
+    }
+}
+
+impl Io for Output {
+    fn print(&self, text: &str) {
+        self.text.borrow_mut().push_str(text);
+    }
+
+    fn flush(&self) {}
+
+    fn read_line(&self) -> String {
+        self.inner.read_line()
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.inner.read_file(path)
+    }
+
+    fn write_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.inner.write_file(path, data)
+    }
+
+    fn append_file(&self, path: &Path, data: &str) -> io::Result<()> {
+        self.inner.append_file(path, data)
+    }
+
+    fn current_time_millis(&self) -> u128 {
+        self.inner.current_time_millis()
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::Hasher,
+    ops::Deref,
+    ptr,
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    error::{raise, ConsizeError},
+    interpreter::Interpreter,
+    pass::{Context, Pass},
+    preprocessor::{count_compiled_quotation, hash_elements, CONTINUATION_WORDS},
+    stack_element::{print_ir, BuiltIn, Funct, StackElement},
+};
+
+#[cfg(feature = "jit")]
+use crate::jit::{Jit, Native};
+
+/// Level 5: compiles a quotation to bytecode for the [`Vm`]. The result is a
+/// single element that runs the bytecode when it is executed.
+pub struct Bytecode {
+    name: &'static str,
+    vm: Rc<Vm>,
+}
+
+impl Default for Bytecode {
+    fn default() -> Self {
+        Self {
+            name: "bytecode",
+            vm: Rc::default(),
+        }
+    }
+}
+
+#[cfg(feature = "jit")]
+impl Bytecode {
+    /// Like level 5, but hot bytecode is compiled to machine code where the
+    /// host supports it.
+    pub fn jit() -> Self {
+        Self {
+            name: "jit",
+            vm: Rc::new(Vm {
+                jit: Jit::new(),
+                ..Vm::default()
+            }),
+        }
+    }
+}
+
+impl Pass for Bytecode {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self, _: &str, words: &[StackElement], context: &Context) -> Vec<StackElement> {
+        let code = Rc::new(Compiler::new(context.dictionary).compile(words));
+        vec![self.vm.entry(code, 0)]
+    }
+
+    fn compiles(&self) -> bool {
+        true
+    }
+}
+
+pub(crate) enum Op {
+    Push(StackElement),
+    /// A primitive that leaves the callstack alone.
+    Prim(BuiltIn),
+    /// A primitive that reads or replaces the callstack, which therefore has
+    /// to hold the frames of the VM.
+    Continuation(BuiltIn),
+    /// Runs the definition of a self-defined word.
+    Call(String),
+    /// Runs the quotation on top of the datastack, like `call`.
+    CallQuotation,
+    /// Runs the first code unless the element on top of the datastack is
+    /// `f`, like `[ ... ] [ ... ] if` in the prelude.
+    Branch(Rc<Code>, Rc<Code>),
+    /// Leaves an element to the interpreter.
+    Interpret(StackElement),
+    /// A `\` at the end of a quotation, which escapes the element following
+    /// the quotation on the callstack.
+    EscapeNext,
+}
+
+/// Compiled bytecode together with the source of every instruction, in
+/// callstack order, so the rest of a frame can always be turned back into
+/// Consize code.
+pub struct Code {
+    pub(crate) ops: Vec<Op>,
+    sources: Vec<Vec<StackElement>>,
+    #[cfg(feature = "jit")]
+    pub(crate) native: Native,
+}
+
+impl Code {
+    /// The source of the instructions from `pc` on, in callstack order.
+    pub(crate) fn source(&self, pc: usize) -> Vec<StackElement> {
+        self.sources[pc..].iter().rev().flatten().cloned().collect()
+    }
+}
+
+struct Compiler<'a> {
+    dictionary: &'a BTreeMap<String, Rc<Funct>>,
+    read_word_is_noop: bool,
+    read_mapping_is_noop: bool,
+    /// Whether `if` and `when` are defined as in the prelude, so they can be
+    /// compiled to branches.
+    prelude_if: bool,
+    prelude_when: bool,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(dictionary: &'a BTreeMap<String, Rc<Funct>>) -> Self {
+        let defined_as = |word: &str, source: &str| match dictionary.get(word).map(|f| f.deref()) {
+            Some(Funct::SelfDefined(StackElement::SubStack(body)))
+            | Some(Funct::Compiled(body, _)) => print_ir(body, dictionary) == source,
+            _ => false,
+        };
+        let primitives = ["rot", "get", "drop", "swap", "call"].iter().all(|w| {
+            matches!(
+                dictionary.get(*w).map(|f| f.deref()),
+                Some(Funct::BuiltIn(_))
+            )
+        });
+        let prelude_if = primitives
+            && defined_as("if", "choose call")
+            && defined_as("choose", "rot { f, [ swap drop ] } [ drop ] get call");
+
+        Self {
+            dictionary,
+            read_word_is_noop: defined_as("read-word", ""),
+            read_mapping_is_noop: defined_as("read-mapping", ""),
+            prelude_if,
+            prelude_when: prelude_if && defined_as("when", "[ ] if"),
+        }
+    }
+
+    fn compile(&self, words: &[StackElement]) -> Code {
+        let mut code = Code {
+            ops: Vec::new(),
+            sources: Vec::new(),
+            #[cfg(feature = "jit")]
+            native: Native::default(),
+        };
+        self.compile_into(words, &mut code);
+        code
+    }
+
+    fn compile_into(&self, words: &[StackElement], code: &mut Code) {
+        let mut rest = words.iter().rev();
+        while let Some(se) = rest.next() {
+            let op = match se {
+                StackElement::Word(w) if w == "\\" => match rest.next() {
+                    Some(next) => {
+                        code.ops.push(Op::Push(next.clone()));
+                        code.sources.push(vec![next.clone(), se.clone()]);
+                        continue;
+                    }
+                    None => Op::EscapeNext,
+                },
+                StackElement::Word(w) => match self.dictionary.get(w).map(|f| f.deref()) {
+                    Some(Funct::BuiltIn(_)) if w == "call" => Op::CallQuotation,
+                    Some(Funct::BuiltIn(bi)) if CONTINUATION_WORDS.contains(&w.as_str()) => {
+                        Op::Continuation(bi.clone())
+                    }
+                    Some(Funct::BuiltIn(bi)) => Op::Prim(bi.clone()),
+                    Some(Funct::SelfDefined(StackElement::SubStack(_)))
+                    | Some(Funct::Compiled(..)) => {
+                        if self.branch(w, code) {
+                            continue;
+                        }
+                        Op::Call(w.clone())
+                    }
+                    Some(Funct::SelfDefined(_)) => Op::Interpret(se.clone()),
+                    None if self.read_word_is_noop => Op::Push(se.clone()),
+                    None => Op::Interpret(se.clone()),
+                },
+                StackElement::Map(_) if !self.read_mapping_is_noop => Op::Interpret(se.clone()),
+                StackElement::SubStack(_) | StackElement::Map(_) | StackElement::Nil => {
+                    Op::Push(se.clone())
+                }
+                StackElement::Fun(f) => match f.deref() {
+                    Funct::Compiled(src, _) | Funct::SelfDefined(StackElement::SubStack(src)) => {
+                        self.compile_into(src, code);
+                        continue;
+                    }
+                    Funct::BuiltIn(bi) => Op::Continuation(bi.clone()),
+                    Funct::SelfDefined(_) => Op::Interpret(se.clone()),
+                },
+            };
+            code.ops.push(op);
+            code.sources.push(vec![se.clone()]);
+        }
+    }
+
+    /// Turns `[ then ] [ else ] if` and `[ then ] when` at the end of `code`
+    /// into a branch.
+    fn branch(&self, word: &str, code: &mut Code) -> bool {
+        let quotations = match word {
+            "if" if self.prelude_if => 2,
+            "when" if self.prelude_when => 1,
+            _ => return false,
+        };
+        let len = code.ops.len();
+        if len < quotations
+            || !code.ops[len - quotations..]
+                .iter()
+                .all(|op| matches!(op, Op::Push(StackElement::SubStack(_))))
+        {
+            return false;
+        }
+
+        let mut branches: Vec<Rc<Code>> = code
+            .ops
+            .drain(len - quotations..)
+            .map(|op| match op {
+                Op::Push(StackElement::SubStack(qt)) => Rc::new(self.compile(&qt)),
+                _ => unreachable!(),
+            })
+            .collect();
+        let otherwise = match quotations {
+            2 => branches.pop().unwrap(),
+            _ => Rc::new(self.compile(&[])),
+        };
+        let then = branches.pop().unwrap();
+
+        let mut source = vec![StackElement::Word(word.to_string())];
+        for s in code.sources.drain(len - quotations..).rev() {
+            source.extend(s);
+        }
+        code.ops.push(Op::Branch(then, otherwise));
+        code.sources.push(source);
+        true
+    }
+}
+
+struct Frame {
+    code: Rc<Code>,
+    pc: usize,
+}
+
+/// Runs bytecode in a loop. Calls push frames onto a stack of their own, so
+/// the native stack does not grow. Before a primitive gets to see the
+/// callstack, every frame is put onto it as an element that resumes the
+/// frame and keeps its source, so continuations work as on any other level.
+#[derive(Default)]
+pub struct Vm {
+    cache: RefCell<Cache>,
+    #[cfg(feature = "jit")]
+    jit: Option<Rc<Jit>>,
+}
+
+/// Bytecode for words and quotations, valid for one dictionary.
+#[derive(Default)]
+struct Cache {
+    dictionary: Weak<BTreeMap<String, Rc<Funct>>>,
+    words: HashMap<String, Option<Rc<Code>>>,
+    /// Quotations by the hash of their source.
+    quotations: HashMap<u64, Vec<CachedQuotation>>,
+}
+
+struct CachedQuotation {
+    source: Vec<StackElement>,
+    code: Rc<Code>,
+}
+
+/// The number of quotations the cache holds before it starts over.
+const QUOTATION_CACHE_SIZE: usize = 4096;
+
+impl Cache {
+    fn for_dictionary(&mut self, dictionary: &Rc<BTreeMap<String, Rc<Funct>>>) -> &mut Self {
+        if !ptr::eq(self.dictionary.as_ptr(), Rc::as_ptr(dictionary)) {
+            *self = Self {
+                dictionary: Rc::downgrade(dictionary),
+                ..Self::default()
+            };
+        }
+        self
+    }
+}
+
+impl Vm {
+    /// An element that runs `code` from `pc` on.
+    fn entry(self: &Rc<Self>, code: Rc<Code>, pc: usize) -> StackElement {
+        let source = code.source(pc);
+        let vm = self.clone();
+        StackElement::Fun(Rc::new(Funct::Compiled(
+            source,
+            Rc::new(move |int| {
+                vm.run(
+                    int,
+                    vec![Frame {
+                        code: code.clone(),
+                        pc,
+                    }],
+                )
+            }),
+        )))
+    }
+
+    fn run(self: &Rc<Self>, mut int: Interpreter, mut frames: Vec<Frame>) -> Interpreter {
+        frames.retain(|f| f.pc < f.code.ops.len());
+        while let Some(frame) = frames.last_mut() {
+            let code = frame.code.clone();
+            let pc = frame.pc;
+            frame.pc += 1;
+            if frame.pc == code.ops.len() {
+                frames.pop();
+            }
+            int = int.step();
+
+            match &code.ops[pc] {
+                Op::Push(se) => int.datastack.push(se.clone()),
+                Op::Prim(f) => int = f(int),
+                Op::Continuation(f) => return f(self.suspend(int, frames)),
+                Op::Call(word) => match self.word(word, &int) {
+                    Some(code) => self.enter(&mut int, &mut frames, code),
+                    None => {
+                        int = self.suspend(int, frames);
+                        int.callstack.push(StackElement::Word(word.clone()));
+                        return int;
+                    }
+                },
+                Op::CallQuotation => match int.datastack.pop() {
+                    Some(StackElement::SubStack(qt)) => {
+                        let code = self.quotation(qt, &int);
+                        self.enter(&mut int, &mut frames, code)
+                    }
+                    Some(other) => {
+                        int.datastack.push(other);
+                        return self.suspend(int, frames).call();
+                    }
+                    None => panic!("call needs a quotation"),
+                },
+                Op::Branch(then, otherwise) => {
+                    let code = match int.datastack.pop().unwrap() {
+                        StackElement::Word(w) if w == "f" => otherwise,
+                        _ => then,
+                    };
+                    // A branch is compiled together with the code around it.
+                    self.push(&mut int, &mut frames, code.clone())
+                }
+                Op::Interpret(se) => {
+                    int = self.suspend(int, frames);
+                    int.callstack.push(se.clone());
+                    return int;
+                }
+                Op::EscapeNext => {
+                    // `\` takes the next element of the source, which may be
+                    // part of a frame, so the frames are put back as source.
+                    for frame in frames {
+                        int.callstack.extend(frame.code.source(frame.pc));
+                    }
+                    int.callstack.push(StackElement::Word("\\".to_string()));
+                    return int;
+                }
+            }
+        }
+
+        int
+    }
+
+    /// Runs the code of a word or quotation, natively if the JIT compiled it.
+    fn enter(&self, int: &mut Interpreter, frames: &mut Vec<Frame>, code: Rc<Code>) {
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &self.jit {
+            if !code.ops.is_empty() && jit.run(self, &code, int) {
+                return;
+            }
+        }
+        self.push(int, frames, code);
+    }
+
+    fn push(&self, int: &mut Interpreter, frames: &mut Vec<Frame>, code: Rc<Code>) {
+        if code.ops.is_empty() {
+            return;
+        }
+        frames.push(Frame { code, pc: 0 });
+        if let Some(max) = int.limits.max_callstack {
+            if frames.len() + int.callstack.len() > max {
+                raise(ConsizeError::CallstackOverflow(max));
+            }
+        }
+    }
+
+    /// Puts the frames onto the callstack, the innermost on top.
+    fn suspend(self: &Rc<Self>, mut int: Interpreter, frames: Vec<Frame>) -> Interpreter {
+        for frame in frames {
+            int.callstack.push(self.entry(frame.code, frame.pc));
+        }
+        int
+    }
+
+    pub(crate) fn word(&self, word: &str, int: &Interpreter) -> Option<Rc<Code>> {
+        let mut cache = self.cache.borrow_mut();
+        let cache = cache.for_dictionary(&int.dictionary);
+        if let Some(code) = cache.words.get(word) {
+            return code.clone();
+        }
+
+        let compiler = Compiler::new(&int.dictionary);
+        let code = match int.dictionary.get(word).map(|f| f.deref()) {
+            Some(Funct::SelfDefined(StackElement::SubStack(body)))
+            | Some(Funct::Compiled(body, _)) => Some(Rc::new(compiler.compile(body))),
+            Some(Funct::BuiltIn(_)) => Some(Rc::new(
+                compiler.compile(&[StackElement::Word(word.to_string())]),
+            )),
+            _ => None,
+        };
+        cache.words.insert(word.to_string(), code.clone());
+        code
+    }
+
+    pub(crate) fn quotation(&self, qt: Vec<StackElement>, int: &Interpreter) -> Rc<Code> {
+        let mut cache = self.cache.borrow_mut();
+        let cache = cache.for_dictionary(&int.dictionary);
+        let mut hasher = DefaultHasher::new();
+        hash_elements(&qt, &mut hasher);
+        let key = hasher.finish();
+        if let Some(CachedQuotation { code, .. }) = cache
+            .quotations
+            .get(&key)
+            .and_then(|entries| entries.iter().find(|entry| entry.source == qt))
+        {
+            return code.clone();
+        }
+
+        let code = Rc::new(Compiler::new(&int.dictionary).compile(&qt));
+        count_compiled_quotation();
+        if cache.quotations.len() >= QUOTATION_CACHE_SIZE {
+            cache.quotations.clear();
+        }
+        cache
+            .quotations
+            .entry(key)
+            .or_default()
+            .push(CachedQuotation {
+                source: qt,
+                code: code.clone(),
+            });
+        code
+    }
+}
@@ -0,0 +1,98 @@
+mod common;
+
+use std::{collections::BTreeSet, fs, rc::Rc};
+
+use common::with_prelude;
+use consize_interpreter::{
+    interpreter::Interpreter,
+    io::MemoryIo,
+    runner::{self, Prelude},
+    stack_element::{print_stack, StackElement},
+};
+
+/// The bootimage the prelude in `int` dumps.
+fn dump(int: Interpreter) -> String {
+    let io = Rc::new(MemoryIo::new());
+    runner::dump_bootimage(int.with_io(io.clone()), "bootimage.txt");
+    io.file("bootimage.txt").unwrap()
+}
+
+#[test]
+fn the_bootimage_is_up_to_date() {
+    with_prelude(|int| {
+        assert_eq!(dump(int), fs::read_to_string("bootimage.txt").unwrap());
+    });
+}
+
+/// `dump` merges the bootimage into the dictionary, so primitives like
+/// `call` keep their native definition until the prelude replaces them.
+#[test]
+fn booting_adds_the_bootstrapping_words() {
+    with_prelude(|int| {
+        let primitives = runner::primitives();
+        let int = runner::run(int, "bootstrapping-dict keys", 0);
+        let expected: BTreeSet<String> = match int.datastack.last() {
+            Some(StackElement::SubStack(result)) => match result.as_slice() {
+                [StackElement::SubStack(keys)] => keys
+                    .iter()
+                    .map(|k| k.to_string())
+                    .filter(|k| !primitives.dictionary.contains_key(k))
+                    .collect(),
+                _ => panic!("no keys"),
+            },
+            _ => panic!("no result"),
+        };
+
+        let booted = runner::boot(primitives.clone(), "bootimage.txt");
+        let added: BTreeSet<String> = booted
+            .dictionary
+            .keys()
+            .filter(|word| !primitives.dictionary.contains_key(*word))
+            .cloned()
+            .collect();
+        assert_eq!(added, expected);
+    });
+}
+
+#[test]
+fn a_booted_interpreter_reads_definitions_and_quotations() {
+    with_prelude(|_| {
+        let int = runner::boot(runner::primitives(), "bootimage.txt");
+        let int = runner::run(int, ": sq dup * ; [ 3 sq ] call { a b } \\ x", 0);
+        assert_eq!(
+            print_stack(&int.datastack, false, false),
+            "[ x { a, b } 9 ] "
+        );
+    });
+}
+
+#[test]
+fn a_dumped_bootimage_boots_to_the_same_bootimage() {
+    with_prelude(|int| {
+        let image = dump(int);
+        let prelude = fs::read_to_string("prelude-plain.txt").unwrap();
+        let io = MemoryIo::new()
+            .with_file("bootimage.txt", &image)
+            .with_file("prelude-plain.txt", &prelude);
+        let booted = runner::boot(runner::primitives().with_io(Rc::new(io)), "bootimage.txt");
+        let prelude = Prelude::File("prelude-plain.txt".into());
+        assert_eq!(dump(runner::load(booted, &prelude).unwrap()), image);
+    });
+}
+
+/// `spit` and `spit-on` take the data on top of the file name,
+/// `( file-wrd data-wrd -- )`. `dump` relies on it.
+#[test]
+fn spit_takes_the_data_on_top_of_the_file() {
+    let io = Rc::new(MemoryIo::new().with_file("log.txt", "a"));
+    let word = |w: &str| StackElement::Word(w.to_string());
+    let mut int = runner::primitives().with_io(io.clone());
+    int.datastack = vec![word("out.txt"), word("b")];
+    let mut int = int.spit();
+    int.datastack = vec![word("log.txt"), word("c")];
+    let int = int.spit_on();
+
+    assert!(int.datastack.is_empty());
+    assert_eq!(io.file("out.txt").unwrap(), "b");
+    assert_eq!(io.file("log.txt").unwrap(), "ac");
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use std::{fs, process, rc::Rc};
+
+use common::with_prelude;
+use consize_interpreter::{
+    cache::{self, Cache},
+    interpreter::Interpreter,
+    pass::Pipeline,
+    runner::{self, Prelude},
+    stack_element::{print_stack, Funct, StackElement},
+};
+
+const PROGRAM: &str =
+    ": sq dup * ; \\ sq ( inline ) pragmas 7 sq [ 1 2 ] \\ x { a b } 3 4 [ + ] call";
+
+fn run(int: Interpreter, level: u8) -> String {
+    print_stack(&runner::run(int, PROGRAM, level).datastack, false, false)
+}
+
+#[test]
+fn plain_levels_survive_a_round_trip() {
+    with_prelude(|_| {
+        let primitives = runner::primitives();
+        let int = runner::load_prelude(primitives.clone());
+        for level in 0..=2 {
+            let optimised = runner::optimise(int.clone(), level);
+            let saved = cache::save(&optimised, &primitives.dictionary).unwrap();
+            let restored = cache::restore(primitives.clone(), &saved).unwrap();
+
+            assert_eq!(
+                cache::save(&restored, &primitives.dictionary).unwrap(),
+                saved,
+                "level {level}"
+            );
+            assert_eq!(restored.pragmas, optimised.pragmas, "level {level}");
+            assert_eq!(run(restored, level), run(optimised, level), "level {level}");
+        }
+    });
+}
+
+#[test]
+fn compiled_levels_are_not_saved() {
+    with_prelude(|_| {
+        let primitives = runner::primitives();
+        let int = runner::load_prelude(primitives.clone());
+        for level in 3..=5 {
+            let optimised = runner::optimise(int.clone(), level);
+            assert!(cache::save(&optimised, &primitives.dictionary).is_err());
+        }
+    });
+}
+
+#[test]
+fn odd_words_survive_a_round_trip() {
+    let primitives = runner::primitives();
+    let mut int = primitives.clone();
+    let mut dictionary = (*int.dictionary).clone();
+    let odd = StackElement::SubStack(vec![
+        StackElement::Word("a b%c\nd".to_string()),
+        StackElement::Word(String::new()),
+        StackElement::Nil,
+    ]);
+    dictionary.insert(
+        "odd word".to_string(),
+        Rc::new(Funct::SelfDefined(odd.clone())),
+    );
+    int.dictionary = Rc::new(dictionary);
+
+    let saved = cache::save(&int, &primitives.dictionary).unwrap();
+    let restored = cache::restore(primitives, &saved).unwrap();
+    assert!(matches!(
+        restored.dictionary["odd word"].as_ref(),
+        Funct::SelfDefined(body) if *body == odd
+    ));
+}
+
+#[test]
+fn invalid_pragmas_are_not_restored() {
+    for pragmas in ["level 9", "level", "inline no-optimise", "fast"] {
+        let cached = format!("consize-cache 1\npragma 'x {pragmas}");
+        assert!(
+            cache::restore(runner::primitives(), &cached).is_err(),
+            "{pragmas}"
+        );
+    }
+}
+
+#[test]
+fn the_cache_is_written_once_and_read_afterwards() {
+    with_prelude(|_| {
+        let dir = std::env::temp_dir().join(format!("consize-cache-test-{}", process::id()));
+        let cache = Cache::new(&dir);
+        let pipeline = Pipeline::level(2);
+
+        let loaded = cache
+            .load_prelude(runner::primitives(), &Prelude::Embedded, &pipeline)
+            .unwrap();
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let path = files[0].as_ref().unwrap().path();
+        let written = fs::read_to_string(&path).unwrap();
+
+        let cached = cache
+            .load_prelude(runner::primitives(), &Prelude::Embedded, &pipeline)
+            .unwrap();
+        assert_eq!(run(cached, 2), run(loaded.clone(), 2));
+
+        // A damaged file is replaced.
+        fs::write(&path, "consize-cache 1\nword x [ 'y").unwrap();
+        let reloaded = cache
+            .load_prelude(runner::primitives(), &Prelude::Embedded, &pipeline)
+            .unwrap();
+        assert_eq!(run(reloaded, 2), run(loaded, 2));
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+
+        fs::remove_dir_all(&dir).unwrap();
+    });
+}
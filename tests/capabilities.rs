@@ -0,0 +1,168 @@
+mod common;
+
+use std::{env, fs, path::PathBuf, process, rc::Rc};
+
+use common::with_prelude;
+use consize_interpreter::{
+    capabilities::{Capabilities, FileAccess},
+    error::{catch, ConsizeError},
+    interpreter::Interpreter,
+    io::MemoryIo,
+    runner,
+    stack_element::print_stack,
+};
+
+/// A fresh directory for one test.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("consize-capabilities-{name}-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(int: &Interpreter, capabilities: &Capabilities, code: &str) -> Result<String, ConsizeError> {
+    let int = int.clone().with_capabilities(capabilities.clone());
+    catch(|| print_stack(&runner::run(int, code, 0).datastack, false, false))
+}
+
+fn denied(result: Result<String, ConsizeError>) -> bool {
+    matches!(result, Err(ConsizeError::AccessDenied(_)))
+}
+
+#[test]
+fn no_io_forbids_files_and_stdin() {
+    with_prelude(|int| {
+        let io = MemoryIo::new()
+            .with_file("in.txt", "data")
+            .with_input("line");
+        let int = int.with_io(Rc::new(io));
+        let no_io = Capabilities::no_io();
+        assert!(denied(run(&int, &no_io, "\\ in.txt slurp")));
+        assert!(denied(run(&int, &no_io, "\\ out.txt \\ x spit")));
+        assert!(denied(run(&int, &no_io, "\\ in.txt \\ x spit-on")));
+        assert!(denied(run(&int, &no_io, "read-line")));
+        assert_eq!(
+            run(&int, &Capabilities::default(), "\\ in.txt slurp"),
+            Ok("[ data ] ".to_string())
+        );
+    });
+}
+
+#[test]
+fn read_only_forbids_writing() {
+    with_prelude(|int| {
+        let io = Rc::new(MemoryIo::new().with_file("in.txt", "data"));
+        let int = int.with_io(io.clone());
+        let read_only = Capabilities {
+            files: FileAccess::ReadOnly,
+            ..Capabilities::default()
+        };
+        assert_eq!(
+            run(&int, &read_only, "\\ in.txt slurp"),
+            Ok("[ data ] ".to_string())
+        );
+        assert!(denied(run(&int, &read_only, "\\ out.txt \\ x spit")));
+        assert!(denied(run(&int, &read_only, "\\ in.txt \\ x spit-on")));
+        assert_eq!(io.file("out.txt"), None);
+        assert_eq!(io.file("in.txt").unwrap(), "data");
+    });
+}
+
+#[test]
+fn no_stdin_forbids_read_line_only() {
+    with_prelude(|int| {
+        let io = MemoryIo::new()
+            .with_file("in.txt", "data")
+            .with_input("line");
+        let int = int.with_io(Rc::new(io));
+        let no_stdin = Capabilities {
+            stdin: false,
+            ..Capabilities::default()
+        };
+        assert!(denied(run(&int, &no_stdin, "read-line")));
+        assert_eq!(
+            run(&int, &no_stdin, "\\ in.txt slurp"),
+            Ok("[ data ] ".to_string())
+        );
+    });
+}
+
+#[test]
+fn root_confines_relative_and_absolute_paths() {
+    with_prelude(|int| {
+        let dir = temp_dir("root");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("in.txt"), "inside").unwrap();
+        fs::write(dir.join("secret.txt"), "outside").unwrap();
+        let confined = Capabilities {
+            root: Some(root.clone()),
+            ..Capabilities::default()
+        };
+
+        assert_eq!(
+            run(&int, &confined, "\\ in.txt slurp \\ sub/../in.txt slurp"),
+            Ok("[ inside inside ] ".to_string())
+        );
+        let inside = root.join("in.txt");
+        assert_eq!(
+            run(&int, &confined, &format!("\\ {} slurp", inside.display())),
+            Ok("[ inside ] ".to_string())
+        );
+        assert!(run(&int, &confined, "\\ out.txt \\ x spit").is_ok());
+        assert_eq!(fs::read_to_string(root.join("out.txt")).unwrap(), "x");
+
+        let outside = dir.join("secret.txt");
+        for path in [
+            "../secret.txt".to_string(),
+            "sub/../../secret.txt".to_string(),
+            outside.display().to_string(),
+        ] {
+            assert!(
+                denied(run(&int, &confined, &format!("\\ {path} slurp"))),
+                "{path}"
+            );
+            assert!(
+                denied(run(&int, &confined, &format!("\\ {path} \\ x spit"))),
+                "{path}"
+            );
+        }
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "outside");
+
+        fs::remove_dir_all(&dir).unwrap();
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn root_is_not_escaped_through_symbolic_links() {
+    with_prelude(|int| {
+        let dir = temp_dir("links");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        fs::write(dir.join("elsewhere/secret.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), root.join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere/secret.txt"), root.join("file")).unwrap();
+        std::os::unix::fs::symlink("../outside.txt", root.join("dangling.txt")).unwrap();
+        let confined = Capabilities {
+            root: Some(root.clone()),
+            ..Capabilities::default()
+        };
+
+        assert!(denied(run(&int, &confined, "\\ link/secret.txt slurp")));
+        assert!(denied(run(&int, &confined, "\\ file slurp")));
+        assert!(denied(run(&int, &confined, "\\ link/new.txt \\ x spit")));
+        assert!(denied(run(&int, &confined, "\\ file \\ x spit")));
+        assert!(denied(run(&int, &confined, "\\ dangling.txt \\ x spit")));
+        assert!(denied(run(&int, &confined, "\\ dangling.txt \\ x spit-on")));
+        assert!(!dir.join("elsewhere/new.txt").exists());
+        assert!(!dir.join("outside.txt").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("elsewhere/secret.txt")).unwrap(),
+            "outside"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    });
+}
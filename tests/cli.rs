@@ -0,0 +1,93 @@
+use std::process::{Command, Output};
+
+/// Runs the binary with `args` and no prelude cache.
+fn consize(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_consize-interpreter"))
+        .arg("--no-cache")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// The error the binary reports on stderr when it fails without a panic.
+fn error(output: &Output) -> String {
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(output));
+    let stderr = stderr(output);
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    stderr.trim_end().to_string()
+}
+
+#[test]
+fn limits_apply_to_the_program_only() {
+    let output = consize(&[
+        "--max-callstack",
+        "20",
+        "--max-elements",
+        "500",
+        "-e",
+        "1 2 +",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("[ 3 ]"));
+
+    let down = ": down ( n -- ) dup 0 > [ 1 - down 0 drop ] when ; 100 down";
+    let output = consize(&["--max-callstack", "50", "-e", down]);
+    assert!(error(&output).ends_with("callstack exceeded 50 elements"));
+}
+
+#[test]
+fn unusable_session_logs_are_errors() {
+    let missing = "no/such/dir/session.log";
+    let output = consize(&["--replay", missing, "-e", "1"]);
+    assert!(error(&output).contains(&format!("cannot read {missing}")));
+
+    let output = consize(&["--record", missing, "-e", "1"]);
+    assert!(error(&output).contains(&format!("cannot create {missing}")));
+}
+
+#[test]
+fn unknown_passes_are_rejected() {
+    let output = consize(&["--passes", "inline,unroll", "-e", "1"]);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    assert!(stderr.contains("unknown pass `unroll`"), "{stderr}");
+}
+
+#[test]
+fn levels_are_checked() {
+    for level in ["6", "x"] {
+        let output = consize(&["-l", level, "-e", "1"]);
+        assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+        assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+    }
+    let output = consize(&["-l", "5", "-e", "1 2 +"]);
+    assert!(stdout(&output).contains("[ 3 ]"), "{}", stderr(&output));
+}
+
+/// The result a run prints, without the time it took.
+fn returns(output: &Output) -> String {
+    let stdout = stdout(output);
+    stdout.split("Took").next().unwrap().to_string()
+}
+
+#[test]
+fn seeds_make_runs_reproducible() {
+    let code = "current-time-millis current-time-millis operating-system";
+    let run = |seed| consize(&["--deterministic", "--seed", seed, "-e", code]);
+    assert_eq!(returns(&run("7")), returns(&run("7")));
+    assert!(
+        returns(&run("7")).contains("[ consize 8 7 ]"),
+        "{}",
+        stdout(&run("7"))
+    );
+    assert_ne!(returns(&run("7")), returns(&run("8")));
+}
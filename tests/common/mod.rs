@@ -0,0 +1,44 @@
+use std::{panic, thread};
+
+use consize_interpreter::{
+    interpreter::Interpreter, pass::Pipeline, runner, stack_element::print_stack,
+};
+
+/// Level 4 nests native calls deeply, so the tests get the same stack size
+/// as the binary.
+const STACK_SIZE: usize = 1 << 30;
+
+/// Runs `f` with a freshly loaded prelude on a thread with a large stack.
+pub fn with_prelude(f: impl FnOnce(Interpreter) + Send + 'static) {
+    let result = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || f(runner::load_prelude(runner::primitives())))
+        .unwrap()
+        .join();
+    if let Err(err) = result {
+        panic::resume_unwind(err);
+    }
+}
+
+/// Defines `words` at level 0, optimises for `pipeline`, then runs every
+/// program in turn transformed by `pipeline` and returns the interpreter
+/// after the last one.
+#[allow(dead_code)]
+pub fn run_all(
+    int: Interpreter,
+    words: &str,
+    pipeline: &Pipeline,
+    programs: &[&str],
+) -> Interpreter {
+    let mut int = runner::optimise_with(runner::run(int, words, 0), pipeline);
+    for program in programs {
+        int = runner::run_with(int, program, pipeline);
+    }
+    int
+}
+
+/// The datastack of `int` as the binary prints it.
+#[allow(dead_code)]
+pub fn datastack(int: &Interpreter) -> String {
+    print_stack(&int.datastack, false, false)
+}
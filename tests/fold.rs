@@ -0,0 +1,103 @@
+mod common;
+
+use common::with_prelude;
+use consize_interpreter::{
+    interpreter::Interpreter,
+    pass::Pipeline,
+    runner,
+    stack_element::{print_ir, StackElement},
+};
+
+/// The program `code` after the given passes, in reading order.
+fn folded(int: &Interpreter, passes: &str, code: &str) -> String {
+    let mut int = int.clone();
+    int.datastack = vec![StackElement::Word(code.to_string())];
+    let mut int = runner::transform(int, &Pipeline::parse(passes).unwrap());
+    match int.datastack.pop() {
+        Some(StackElement::SubStack(program)) => print_ir(&program, &int.dictionary),
+        _ => panic!("no program"),
+    }
+}
+
+#[test]
+fn literal_arithmetic_is_evaluated() {
+    with_prelude(|int| {
+        assert_eq!(folded(&int, "fold", "0 1 + 2 * 10 swap -"), "8");
+        assert_eq!(folded(&int, "fold", "3 4 < 4 4 =="), "\\ t \\ t");
+        assert_eq!(folded(&int, "fold", "x 7 3 mod +"), "x 1 +");
+    });
+}
+
+#[test]
+fn literals_are_shuffled() {
+    with_prelude(|int| {
+        assert_eq!(folded(&int, "fold", "\\ x drop 5"), "5");
+        assert_eq!(folded(&int, "fold", "\\ x \\ y swap"), "\\ y \\ x");
+        assert_eq!(folded(&int, "fold", "1 2 3 rot dup"), "2 3 1 1");
+        assert_eq!(folded(&int, "fold", "[ a ] dup"), "[ a ] [ a ]");
+    });
+}
+
+#[test]
+fn identities_are_removed() {
+    with_prelude(|int| {
+        assert_eq!(
+            folded(&int, "fold", "x swap swap dup drop rot rot rot"),
+            "x"
+        );
+        assert_eq!(folded(&int, "fold", "x swap dup drop swap"), "x");
+        assert_eq!(folded(&int, "fold", "x swap y swap"), "x swap y swap");
+    });
+}
+
+#[test]
+fn quotations_are_folded_only_when_called() {
+    with_prelude(|int| {
+        assert_eq!(folded(&int, "fold", "[ 1 2 + ] call"), "[ 3 ] call");
+        assert_eq!(folded(&int, "fold", "[ 1 2 + ] size"), "[ 1 2 + ] size");
+        assert_eq!(
+            folded(&int, "fold,resolve", "[ x 1 2 + swap ] call"),
+            "[ x 3 <swap> ] call"
+        );
+    });
+}
+
+#[test]
+fn failing_primitives_are_left_alone() {
+    with_prelude(|int| {
+        assert_eq!(folded(&int, "fold", "7 0 div"), "7 0 div");
+        assert_eq!(
+            folded(&int, "fold", "9223372036854775807 1 +"),
+            "9223372036854775807 1 +"
+        );
+        assert_eq!(folded(&int, "fold", "\\ x 1 +"), "\\ x 1 +");
+    });
+}
+
+#[test]
+fn escaped_and_redefined_words_are_not_folded() {
+    with_prelude(|int| {
+        assert_eq!(folded(&int, "fold", "\\ swap swap"), "\\ swap swap");
+
+        let int = runner::run(int, ": + * ; : 3 4 ;", 0);
+        assert_eq!(folded(&int, "fold", "1 2 +"), "1 2 +");
+        assert_eq!(folded(&int, "fold", "1 3 swap"), "1 3 swap");
+    });
+}
+
+#[test]
+fn folding_keeps_results() {
+    with_prelude(|int| {
+        let code = "5 0 1 + 2 * dup drop swap swap 3 < \\ x drop ( 1 2 3 ) [ 2 * 1 + ] map";
+        let expected = runner::run(int.clone(), code, 0).datastack;
+        for passes in ["fold", "inline,fold", "inline,fold,resolve", "compile,fold"] {
+            let pipeline = Pipeline::parse(passes).unwrap();
+            let int = runner::optimise_with(int.clone(), &pipeline);
+            assert_eq!(
+                runner::run_with(int, code, &pipeline).datastack,
+                expected,
+                "passes {passes}"
+            );
+        }
+    });
+}
@@ -0,0 +1,91 @@
+#![cfg(feature = "jit")]
+
+mod common;
+
+use common::{datastack, run_all, with_prelude};
+use consize_interpreter::{jit::kernel_runs, pass::Pipeline};
+
+#[test]
+fn hot_loops_agree_with_bytecode() {
+    with_prelude(|int| {
+        let words = ": count ( n -- n ) dup 1000 < [ 1 + count ] when ;
+                     : sum ( acc n -- acc ) dup 0 > [ swap over + swap 1 - sum ] [ drop ] if ;";
+        let programs = ["0 count 0 500 sum 0 [ 1 + dup 200 < ] loop"];
+        let before = kernel_runs();
+        let jit = datastack(&run_all(
+            int.clone(),
+            words,
+            &Pipeline::parse("jit").unwrap(),
+            &programs,
+        ));
+        assert!(kernel_runs() > before, "no machine code ran");
+        assert_eq!(
+            jit,
+            datastack(&run_all(
+                int,
+                words,
+                &Pipeline::parse("bytecode").unwrap(),
+                &programs
+            ))
+        );
+        assert_eq!(jit, "[ 200 125250 1000 ] ");
+    });
+}
+
+#[test]
+fn kernels_fall_back_on_other_elements() {
+    with_prelude(|int| {
+        let words = ": square ( n -- n ) dup * ;";
+        let mut programs = vec!["2 square"; 100];
+        programs.push("007 square \\ x \\ y swap");
+        assert_eq!(
+            datastack(&run_all(
+                int,
+                words,
+                &Pipeline::parse("jit").unwrap(),
+                &programs
+            )),
+            "[ x y 49 ] "
+        );
+    });
+}
+
+#[test]
+fn kernels_see_redefinitions() {
+    with_prelude(|int| {
+        let words = ": inc 1 + ; : count ( n -- n ) dup 100 < [ inc count ] when ;";
+        let programs = ["0 count", ": inc 7 + ;", "0 count"];
+        assert_eq!(
+            datastack(&run_all(
+                int,
+                words,
+                &Pipeline::parse("jit").unwrap(),
+                &programs
+            )),
+            "[ 105 ] "
+        );
+    });
+}
+
+#[test]
+fn code_moving_stacks_agrees_with_bytecode() {
+    with_prelude(|int| {
+        let programs = ["1 300 [a,b] 0 [ + ] reduce"];
+        let jit = datastack(&run_all(
+            int.clone(),
+            "",
+            &Pipeline::parse("jit").unwrap(),
+            &programs,
+        ));
+        assert_eq!(
+            jit,
+            datastack(&run_all(
+                int,
+                "",
+                &Pipeline::parse("bytecode").unwrap(),
+                &programs
+            ))
+        );
+        assert_eq!(jit, "[ 45150 ] ");
+    });
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::with_prelude;
+use consize_interpreter::{
+    error::{catch, ConsizeError},
+    interpreter::Interpreter,
+    limits::Limits,
+    pass::MAX_LEVEL,
+    runner,
+    stack_element::print_stack,
+};
+
+/// Runs `code` on `int` with `limits` from step 0 on.
+fn run(int: Interpreter, limits: Limits, code: &str) -> Result<String, ConsizeError> {
+    let mut int = int.with_limits(limits);
+    int.steps = 0;
+    catch(|| print_stack(&runner::run(int, code, 0).datastack, false, false))
+}
+
+#[test]
+fn a_full_datastack_aborts() {
+    with_prelude(|int| {
+        let limits = Limits {
+            max_datastack: Some(5),
+            ..Limits::default()
+        };
+        assert_eq!(
+            run(int.clone(), limits, "1 2 3 4 5"),
+            Ok("[ 5 4 3 2 1 ] ".to_string())
+        );
+        let err = run(int, limits, "1 2 3 4 5 6").unwrap_err();
+        assert_eq!(err, ConsizeError::DatastackOverflow(5));
+        assert_eq!(err.to_string(), "datastack exceeded 5 elements");
+    });
+}
+
+#[test]
+fn a_full_callstack_aborts() {
+    with_prelude(|int| {
+        let int = runner::run(int, ": down ( n -- ) dup 0 > [ 1 - down 0 drop ] when ;", 0);
+        let limits = Limits {
+            max_callstack: Some(50),
+            ..Limits::default()
+        };
+        assert!(run(int.clone(), limits, "3 down").is_ok());
+        let err = run(int, limits, "100 down").unwrap_err();
+        assert_eq!(err, ConsizeError::CallstackOverflow(50));
+        assert_eq!(err.to_string(), "callstack exceeded 50 elements");
+    });
+}
+
+#[test]
+fn too_many_elements_abort() {
+    with_prelude(|int| {
+        let limits = Limits {
+            max_elements: Some(1000),
+            ..Limits::default()
+        };
+        assert!(run(int.clone(), limits, "[ 1 2 3 4 5 6 7 8 9 10 11 12 ]").is_ok());
+
+        let many = format!("[ {}]", "1 ".repeat(2000));
+        let err = run(int, limits, &many).unwrap_err();
+        assert_eq!(err, ConsizeError::ElementOverflow(1000));
+        assert_eq!(err.to_string(), "stacks hold more than 1000 elements");
+    });
+}
+
+/// Every `dup` doubles the stack, far faster than the stacks are recounted.
+#[test]
+fn doubling_aborts_before_memory_runs_out() {
+    with_prelude(|int| {
+        let int = runner::run(int, ": grow ( s -- s ) dup concat grow ;", 0);
+        let limits = Limits {
+            max_elements: Some(100_000),
+            ..Limits::default()
+        };
+        for level in 0..=MAX_LEVEL {
+            let mut int = int.clone().with_limits(limits);
+            int.steps = 0;
+            let err = catch(|| runner::run(int, "( 1 ) grow", level).steps).unwrap_err();
+            assert_eq!(err, ConsizeError::ElementOverflow(100_000), "level {level}");
+        }
+    });
+}
+
+/// `apply` runs a function as a nested native call.
+#[test]
+fn deep_nesting_aborts() {
+    with_prelude(|int| {
+        let int = runner::run(
+            int,
+            ": nest ( n -- ) dup 0 > [ 1 - [ ] swap push [ nest ] get-dict func apply drop ] [ drop ] if ;",
+            0,
+        );
+        let limits = Limits {
+            max_nesting: Some(10),
+            ..Limits::default()
+        };
+        assert!(run(int.clone(), limits, "3 nest").is_ok());
+        let err = run(int, limits, "100 nest").unwrap_err();
+        assert_eq!(err, ConsizeError::NestingOverflow(10));
+        assert_eq!(err.to_string(), "nesting depth exceeded 10 levels");
+    });
+}
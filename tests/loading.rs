@@ -0,0 +1,103 @@
+mod common;
+
+use std::{io::ErrorKind, rc::Rc};
+
+use common::with_prelude;
+use consize_interpreter::{
+    io::MemoryIo,
+    runner::{self, Prelude},
+    stack_element::print_stack,
+};
+
+#[test]
+fn the_embedded_prelude_needs_no_files() {
+    with_prelude(|_| {
+        let io = Rc::new(MemoryIo::new());
+        let int = runner::load_prelude(runner::primitives().with_io(io.clone()));
+        let int = runner::run(int, "{ \\ a 1 } \\ out.txt \\ hello spit", 0);
+        assert_eq!(print_stack(&int.datastack, false, false), "[ { a, 1 } ] ");
+        assert_eq!(io.file("out.txt").unwrap(), "hello");
+    });
+}
+
+#[test]
+fn a_prelude_file_is_read_through_the_io() {
+    with_prelude(|_| {
+        let io = MemoryIo::new()
+            .with_file("bootimage.txt", runner::BOOTIMAGE)
+            .with_file(
+                "mini.txt",
+                "\\ bootimage.txt run : twice ( x -- x x ) dup ;",
+            );
+        let int = runner::primitives().with_io(Rc::new(io));
+        let int = runner::load(int, &Prelude::File("mini.txt".into())).unwrap();
+        assert!(!int.dictionary.contains_key("repl"));
+        let int = runner::run(int, "3 twice", 0);
+        assert_eq!(print_stack(&int.datastack, false, false), "[ 3 3 ] ");
+    });
+}
+
+#[test]
+fn a_prelude_file_boots_from_the_bootimage_next_to_it() {
+    with_prelude(|_| {
+        let prelude = "\\ bootimage.txt run : twice ( x -- x x ) dup ;";
+        let io = MemoryIo::new()
+            .with_file("lib/bootimage.txt", runner::BOOTIMAGE)
+            .with_file("lib/mini.txt", prelude)
+            .with_file("elsewhere/mini.txt", prelude);
+        let int = runner::primitives().with_io(Rc::new(io));
+        for path in ["lib/mini.txt", "elsewhere/mini.txt"] {
+            let int = runner::load(int.clone(), &Prelude::File(path.into())).unwrap();
+            let int = runner::run(int, "3 twice", 0);
+            assert_eq!(
+                print_stack(&int.datastack, false, false),
+                "[ 3 3 ] ",
+                "{path}"
+            );
+        }
+    });
+}
+
+#[test]
+fn unreadable_files_are_errors() {
+    let int = runner::primitives().with_io(Rc::new(MemoryIo::new()));
+    let Err(err) = runner::load(int.clone(), &Prelude::File("missing.txt".into())) else {
+        panic!("missing.txt was read");
+    };
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(err.to_string().starts_with("cannot read missing.txt"));
+    assert!(runner::load_file(int, "missing.txt".as_ref()).is_err());
+}
+
+#[test]
+fn no_prelude_leaves_the_primitives() {
+    let primitives = runner::primitives();
+    let int = runner::load(primitives.clone(), &Prelude::None).unwrap();
+    assert!(Rc::ptr_eq(&int.dictionary, &primitives.dictionary));
+}
+
+#[test]
+fn preloaded_files_extend_the_prelude() {
+    with_prelude(|int| {
+        let io = MemoryIo::new().with_file("words.txt", ": twice ( x -- x x ) dup ;");
+        let int = runner::load_file(int.with_io(Rc::new(io)), "words.txt".as_ref()).unwrap();
+        let int = runner::run(int, "[ 1 ] twice concat", 0);
+        assert_eq!(print_stack(&int.datastack, false, false), "[ [ 1 1 ] ] ");
+    });
+}
+
+#[test]
+fn args_are_pushed_with_the_first_on_top() {
+    with_prelude(|int| {
+        let args = ["7".to_string(), "two words".to_string()];
+        for level in 0..=5 {
+            let int = runner::with_args(runner::optimise(int.clone(), level), &args);
+            let int = runner::run(int, "args dup top dup *", level);
+            assert_eq!(
+                print_stack(&int.datastack, false, false),
+                "[ 49 [ 7 two words ] ] ",
+                "level {level}"
+            );
+        }
+    });
+}
@@ -0,0 +1,241 @@
+mod common;
+
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+};
+
+use common::with_prelude;
+use consize_interpreter::{
+    cache,
+    interpreter::Interpreter,
+    io::MemoryIo,
+    pass::Pipeline,
+    runner::{self, Prelude},
+    stack_element::{print_stack, StackElement},
+    tiering::Tiering,
+};
+
+/// A piece of prelude-test.txt: either a `unit-test` or a statement later
+/// tests depend on, like a definition.
+enum Chunk {
+    Test { line: usize, code: String },
+    Setup(String),
+}
+
+/// Splits the file into chunks. A chunk starts at an unindented line and
+/// continues over indented lines.
+fn chunks() -> Vec<Chunk> {
+    let source = fs::read_to_string("prelude-test.txt").unwrap();
+    let mut chunks: Vec<(usize, String)> = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split('%').next().unwrap().trim_end();
+        if code.trim().is_empty() {
+            continue;
+        }
+        match chunks.last_mut() {
+            Some((_, chunk)) if code.starts_with(char::is_whitespace) => {
+                chunk.push(' ');
+                chunk.push_str(code.trim());
+            }
+            _ => chunks.push((i + 1, code.to_string())),
+        }
+    }
+
+    chunks
+        .into_iter()
+        .map(|(line, code)| match code.strip_suffix("unit-test") {
+            Some(test) => Chunk::Test {
+                line,
+                code: test.trim_end().to_string(),
+            },
+            None => Chunk::Setup(code),
+        })
+        .collect()
+}
+
+fn run_prelude_tests(level: u8) {
+    with_prelude(move |int| run_with(int, &Pipeline::level(level), &format!("level {level}")));
+}
+
+/// Runs the tests at level 0, promoting words to `level` after two calls.
+fn run_prelude_tests_adaptive(level: u8) {
+    with_prelude(move |int| {
+        let int = int.with_tiering(Tiering::new(level, 2));
+        run_with(
+            int,
+            &Pipeline::default(),
+            &format!("adaptive level {level}"),
+        )
+    });
+}
+
+fn run_prelude_tests_with(passes: &'static str) {
+    with_prelude(move |int| {
+        let pipeline = Pipeline::parse(passes).unwrap();
+        run_with(int, &pipeline, &format!("passes {passes}"))
+    });
+}
+
+/// Runs the tests at level 2 on a dictionary saved to and restored from the
+/// cache format.
+fn run_prelude_tests_restored() {
+    with_prelude(|_| {
+        let primitives = runner::primitives();
+        let int = runner::optimise(runner::load_prelude(primitives.clone()), 2);
+        let saved = cache::save(&int, &primitives.dictionary).unwrap();
+        let int = cache::restore(primitives, &saved).unwrap();
+        run_optimised(int, &Pipeline::level(2), "restored level 2")
+    });
+}
+
+/// Runs the tests at level 0 after booting the primitives from the
+/// bootimage the prelude dumps and loading the prelude on top.
+fn run_prelude_tests_booted() {
+    with_prelude(|int| {
+        let dumped = Rc::new(MemoryIo::new());
+        runner::dump_bootimage(int.with_io(dumped.clone()), "bootimage.txt");
+        let io = MemoryIo::new()
+            .with_file("bootimage.txt", &dumped.file("bootimage.txt").unwrap())
+            .with_file(
+                "prelude-plain.txt",
+                &fs::read_to_string("prelude-plain.txt").unwrap(),
+            );
+        let int = runner::boot(runner::primitives().with_io(Rc::new(io)), "bootimage.txt");
+        let int = runner::load(int, &Prelude::File("prelude-plain.txt".into())).unwrap();
+        run_with(int, &Pipeline::default(), "booted")
+    });
+}
+
+/// Runs every `unit-test` transformed by `pipeline`, reports each case as it
+/// fails and fails with the number of failing cases.
+fn run_with(int: Interpreter, pipeline: &Pipeline, name: &str) {
+    run_optimised(runner::optimise_with(int, pipeline), pipeline, name)
+}
+
+/// Like [`run_with`] for a dictionary already optimised for `pipeline`.
+fn run_optimised(mut int: Interpreter, pipeline: &Pipeline, name: &str) {
+    let mut passed = 0;
+    let mut failed = 0;
+
+    for chunk in chunks() {
+        match chunk {
+            Chunk::Setup(code) => int = run(int, &code, pipeline),
+            Chunk::Test { line, code } => {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    run(int.clone(), &format!("{code} fcall"), pipeline)
+                }));
+                let failure = match result {
+                    Ok(after) => {
+                        let failure = check(&after, line, &code);
+                        int = after;
+                        failure
+                    }
+                    Err(_) => Some(format!("line {line}: {code} panicked")),
+                };
+                match failure {
+                    Some(failure) => {
+                        failed += 1;
+                        println!("FAIL {name} {failure}");
+                    }
+                    None => passed += 1,
+                }
+            }
+        }
+    }
+
+    println!("{name}: {passed} passed, {failed} failed");
+    assert_eq!(
+        failed,
+        0,
+        "{failed} of {} tests failed with {name}",
+        passed + failed
+    );
+}
+
+/// Runs `code` the way `\\ prelude-test.txt run` would, which leaves
+/// quotations to be parsed by the prelude.
+fn run(int: Interpreter, code: &str, pipeline: &Pipeline) -> Interpreter {
+    let io = MemoryIo::new().with_file("case.txt", code);
+    runner::run_with(int.with_io(Rc::new(io)), "\\ case.txt run", pipeline)
+}
+
+fn check(int: &Interpreter, line: usize, code: &str) -> Option<String> {
+    let result = match int.datastack.last() {
+        Some(StackElement::SubStack(result)) => result,
+        _ => return Some(format!("line {line}: {code} left no result")),
+    };
+    match result.as_slice() {
+        [expected, actual] if expected == actual => None,
+        [expected, actual] => Some(format!(
+            "line {line}: {code} expected {expected} but got {actual}"
+        )),
+        _ => Some(format!(
+            "line {line}: {code} left {}",
+            print_stack(result, false, false)
+        )),
+    }
+}
+
+#[test]
+fn prelude_test_level_0() {
+    run_prelude_tests(0);
+}
+
+#[test]
+fn prelude_test_level_1() {
+    run_prelude_tests(1);
+}
+
+#[test]
+fn prelude_test_level_2() {
+    run_prelude_tests(2);
+}
+
+#[test]
+fn prelude_test_level_3() {
+    run_prelude_tests(3);
+}
+
+#[test]
+fn prelude_test_level_4() {
+    run_prelude_tests(4);
+}
+
+#[test]
+fn prelude_test_level_5() {
+    run_prelude_tests(5);
+}
+
+#[test]
+fn prelude_test_adaptive_level_2() {
+    run_prelude_tests_adaptive(2);
+}
+
+#[test]
+fn prelude_test_adaptive_level_4() {
+    run_prelude_tests_adaptive(4);
+}
+
+#[test]
+fn prelude_test_restored_level_2() {
+    run_prelude_tests_restored();
+}
+
+#[test]
+fn prelude_test_booted() {
+    run_prelude_tests_booted();
+}
+
+#[test]
+fn prelude_test_folded() {
+    run_prelude_tests_with("inline,fold,resolve");
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn prelude_test_jit() {
+    run_prelude_tests_with("jit");
+}
@@ -0,0 +1,650 @@
+mod common;
+
+use std::{ops::Deref, rc::Rc};
+
+use common::{datastack, run_all, with_prelude};
+use consize_interpreter::{
+    interpreter::Interpreter,
+    limits::Limits,
+    pass::Pipeline,
+    preprocessor::{preprocess, quotations_compiled, transformed, InlineBudget},
+    runner,
+    stack_element::{count_elements, print_ir, print_stack, Funct, StackElement},
+};
+
+const EVEN_ODD: &str = "
+    : even? ( n -- t/f ) dup 0 == [ drop t ] [ 1 - odd? ] if ;
+    : odd? ( n -- t/f ) dup 0 == [ drop f ] [ 1 - even? ] if ;";
+
+fn body(int: &Interpreter, word: &str) -> Vec<StackElement> {
+    match int.dictionary[word].deref() {
+        Funct::SelfDefined(StackElement::SubStack(body)) => body.clone(),
+        f => panic!("{word} is not self-defined: {f:?}"),
+    }
+}
+
+fn contains_word(words: &[StackElement], word: &str) -> bool {
+    words.iter().any(|se| match se {
+        StackElement::Word(w) => w == word,
+        StackElement::SubStack(ss) => contains_word(ss, word),
+        _ => false,
+    })
+}
+
+#[test]
+fn mutually_recursive_words_stay_calls() {
+    with_prelude(|int| {
+        let int = runner::run(int, EVEN_ODD, 0);
+        let inlined = preprocess(
+            "even?".to_string(),
+            &body(&int, "even?"),
+            &int.dictionary,
+            InlineBudget::UNLIMITED,
+            &int.pragmas,
+        );
+
+        assert!(contains_word(&inlined, "even?"));
+        assert!(!contains_word(&inlined, "odd?"));
+    });
+}
+
+#[test]
+fn mutually_recursive_words_run_at_every_level() {
+    with_prelude(|int| {
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    EVEN_ODD,
+                    &Pipeline::level(level),
+                    &["7 even? 10 even? 3 odd?"]
+                )),
+                "[ t t f ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn shared_words_are_inlined_once_per_run() {
+    with_prelude(|int| {
+        // Expanding every call site anew takes 2^24 steps to optimise `w24`.
+        let mut words = ": w0 ( -- ) 1 drop ;".to_string();
+        for k in 1..=24 {
+            words += &format!(" : w{k} ( -- ) w{0} w{0} ;", k - 1);
+        }
+        for level in 1..=2 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    &words,
+                    &Pipeline::level(level),
+                    &["w2"]
+                )),
+                "[ ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn self_recursive_words_run_at_every_level() {
+    with_prelude(|int| {
+        let countdown = ": countdown ( n -- 0 ) dup 0 > [ 1 - countdown ] when ;";
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    countdown,
+                    &Pipeline::level(level),
+                    &["5 countdown"]
+                )),
+                "[ 0 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn deep_definition_chains_are_cut_off() {
+    with_prelude(|int| {
+        let chain: String = (0..100)
+            .map(|i| format!(": chain{i} chain{} ; ", i + 1))
+            .chain([": chain100 \\ done ;".to_string()])
+            .collect();
+        let int = runner::run(int, &chain, 0);
+        let inlined = preprocess(
+            "chain0".to_string(),
+            &body(&int, "chain0"),
+            &int.dictionary,
+            InlineBudget::UNLIMITED,
+            &int.pragmas,
+        );
+
+        assert!(contains_word(&inlined, "chain32"));
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    "",
+                    &Pipeline::level(level),
+                    &["chain0"]
+                )),
+                "[ done ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+/// A word whose body has 40 elements.
+const MID: &str = ": mid 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20
+    drop drop drop drop drop drop drop drop drop drop
+    drop drop drop drop drop drop drop drop drop drop ;";
+
+#[test]
+fn cold_words_get_a_share_of_the_budget() {
+    with_prelude(|int| {
+        let int = runner::run(int, &format!("{MID} : once mid ;"), 0);
+        let inlined = preprocess(
+            "once".to_string(),
+            &body(&int, "once"),
+            &int.dictionary,
+            InlineBudget::default(),
+            &int.pragmas,
+        );
+
+        assert_eq!(inlined, vec![StackElement::Word("mid".to_string())]);
+    });
+}
+
+#[test]
+fn hot_words_get_the_full_budget() {
+    with_prelude(|int| {
+        let words = format!("{MID} : once mid ; : a mid ; : b mid ; : c mid ;");
+        let int = runner::run(int, &words, 0);
+        let inlined = preprocess(
+            "once".to_string(),
+            &body(&int, "once"),
+            &int.dictionary,
+            InlineBudget::default(),
+            &int.pragmas,
+        );
+
+        assert_eq!(inlined.len(), 40);
+        assert!(!contains_word(&inlined, "mid"));
+    });
+}
+
+#[test]
+fn large_words_stay_calls() {
+    with_prelude(|int| {
+        let small = InlineBudget { max_size: 8 };
+        let size = |budget| {
+            count_elements(&preprocess(
+                "x".to_string(),
+                &body(&int, "unit-test"),
+                &int.dictionary,
+                budget,
+                &int.pragmas,
+            ))
+        };
+        assert!(size(small) < size(InlineBudget::default()));
+        assert!(size(InlineBudget::default()) < size(InlineBudget::UNLIMITED));
+
+        let int = runner::optimise(int.with_inline_budget(small), 1);
+        assert_eq!(
+            print_stack(
+                &runner::run(int, "( 1 2 3 ) [ 4 + ] map", 1).datastack,
+                false,
+                false
+            ),
+            "[ [ 5 6 7 ] ] "
+        );
+    });
+}
+
+#[test]
+fn redefinitions_reach_optimised_callers() {
+    with_prelude(|int| {
+        let words = ": inc 1 + ; : twice inc inc ;";
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    words,
+                    &Pipeline::level(level),
+                    &[": inc 10 + ;", "0 twice"]
+                )),
+                "[ 20 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn redefined_primitives_reach_optimised_callers() {
+    with_prelude(|int| {
+        let words = ": sum3 + + ;";
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    words,
+                    &Pipeline::level(level),
+                    &[": + * ;", "2 3 4 sum3"]
+                )),
+                "[ 24 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn redefinitions_reach_the_rest_of_their_program() {
+    with_prelude(|int| {
+        let programs = [
+            (": nip ( a b -- c ) 99 ; 1 2 nip", "[ 99 2 1 ] "),
+            (": 2dup ( a b -- c ) drop drop 42 ; 1 2 2dup", "[ 42 ] "),
+            (": f dup 0 == [ ] [ 1 - f ] if ; 5 f", "[ 0 ] "),
+            // `2nip` is defined with `nip`, so it has to see the new one.
+            (": nip ( a b -- a ) drop ; 1 2 3 [ 2nip ] call", "[ 1 ] "),
+        ];
+        for level in 0..=5 {
+            for (code, expected) in programs {
+                assert_eq!(
+                    datastack(&run_all(int.clone(), "", &Pipeline::level(level), &[code])),
+                    expected,
+                    "level {level}: {code}"
+                );
+            }
+        }
+    });
+}
+
+#[test]
+fn deleted_words_are_no_longer_inlined() {
+    with_prelude(|int| {
+        let words = ": greeting \\ hello ; : greet greeting ;";
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    words,
+                    &Pipeline::level(level),
+                    &["\\ greeting delete", "greet"]
+                )),
+                "[ greeting ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn unrelated_definitions_are_kept() {
+    with_prelude(|int| {
+        for level in 1..=4 {
+            let int = runner::optimise(int.clone(), level);
+            let before = int.dictionary["unit-test"].clone();
+            let after = runner::run(int, ": unrelated 5 ;", level);
+
+            assert!(Rc::ptr_eq(&before, &after.dictionary["unit-test"]));
+            assert!(after.dictionary.contains_key("unrelated"));
+        }
+    });
+}
+
+#[test]
+fn passes_run_in_any_order() {
+    with_prelude(|int| {
+        let code = "( 1 2 3 ) [ dup * ] map 0 [ + ] reduce [ 1 + ] call";
+        for passes in [
+            "inline,resolve",
+            "resolve",
+            "inline,compile,compose",
+            "compose,inline",
+        ] {
+            let pipeline = Pipeline::parse(passes).unwrap();
+            let int = runner::optimise_with(int.clone(), &pipeline);
+            assert_eq!(
+                print_stack(
+                    &runner::run_with(int, code, &pipeline).datastack,
+                    false,
+                    false
+                ),
+                "[ 15 ] ",
+                "passes {passes}"
+            );
+        }
+    });
+}
+
+#[test]
+fn unknown_passes_are_rejected() {
+    assert_eq!(
+        Pipeline::parse(" inline , resolve").unwrap().to_string(),
+        "inline,resolve"
+    );
+    assert!(Pipeline::parse("inline,unroll")
+        .unwrap_err()
+        .contains("unknown pass `unroll`"));
+}
+
+#[test]
+fn transformed_definitions_show_functions_by_name() {
+    with_prelude(|int| {
+        let emit = |level| {
+            let int = runner::optimise(int.clone(), level);
+            let definitions = transformed(&int, Some("2dup"));
+            assert_eq!(definitions.len(), 1);
+            print_ir(&definitions[0].1, &int.dictionary)
+        };
+        assert_eq!(emit(0), "over over");
+        assert_eq!(emit(1), "swap dup rot rot swap dup rot rot");
+        assert_eq!(emit(2), "<swap> <dup> <rot> <rot> <swap> <dup> <rot> <rot>");
+        assert_eq!(emit(4), "<swap dup rot rot swap dup rot rot>");
+
+        let dup = StackElement::Fun(int.dictionary["dup"].clone());
+        assert_eq!(print_ir(&[dup], &int.dictionary), "<dup>");
+    });
+}
+
+#[test]
+fn compiled_quotations_see_redefinitions() {
+    with_prelude(|int| {
+        let words = ": inc 1 + ;";
+        for level in 3..=5 {
+            let programs = [
+                "0 [ inc ] call",
+                ": inc 10 + ;",
+                "0 [ inc ] call [ inc ] call",
+            ];
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    words,
+                    &Pipeline::level(level),
+                    &programs
+                )),
+                "[ 20 ] ",
+                "level {level}"
+            );
+
+            // Every quotation is compiled once, however often it is called.
+            let compilations = |code: &str| {
+                let int = runner::optimise(runner::run(int.clone(), words, 0), level);
+                let before = quotations_compiled();
+                runner::run(int, code, level);
+                quotations_compiled() - before
+            };
+            let once = compilations("0 [ inc ] call");
+            assert_eq!(
+                compilations("0 [ inc ] call [ inc ] call [ inc ] call"),
+                once
+            );
+            assert_eq!(compilations("0 [ inc ] call [ 2 + ] call"), once + 1);
+        }
+    });
+}
+
+#[test]
+fn long_compositions_run_without_nesting() {
+    with_prelude(|int| {
+        let int = runner::optimise(int, 4).with_limits(Limits {
+            max_nesting: Some(8),
+            ..Limits::default()
+        });
+        let code = "1 ".repeat(1000) + &"+ ".repeat(999);
+        assert_eq!(
+            print_stack(&runner::run(int, &code, 4).datastack, false, false),
+            "[ 1000 ] "
+        );
+    });
+}
+
+#[test]
+fn bytecode_keeps_continuations() {
+    with_prelude(|int| {
+        let programs = [
+            ("1 [ 2 ] [ 3 ] if [ 4 ] call/cc", "[ 4 [ ] [ 2 ] ] "),
+            ("[ drop [ 5 ] ] call/cc 6", "[ [ 5 ] [ ] ] "),
+            ("1 2 [ + ] [ 5 ] dip \\ swap", "[ swap [ + ] 5 2 1 ] "),
+        ];
+        for (code, expected) in programs {
+            assert_eq!(
+                datastack(&run_all(int.clone(), "", &Pipeline::level(5), &[code])),
+                datastack(&run_all(int.clone(), "", &Pipeline::level(0), &[code])),
+                "{code}"
+            );
+            assert_eq!(
+                datastack(&run_all(int.clone(), "", &Pipeline::level(5), &[code])),
+                expected,
+                "{code}"
+            );
+        }
+    });
+}
+
+#[test]
+fn bytecode_recursion_runs_without_nesting() {
+    with_prelude(|int| {
+        let int = runner::optimise(runner::run(int, EVEN_ODD, 0), 5).with_limits(Limits {
+            max_nesting: Some(8),
+            ..Limits::default()
+        });
+        assert_eq!(
+            print_stack(&runner::run(int, "10001 even?", 5).datastack, false, false),
+            "[ f ] "
+        );
+    });
+}
+
+#[test]
+fn bytecode_sees_a_new_read_word() {
+    with_prelude(|int| {
+        let programs = [
+            ": greet hello ;",
+            ": read-word dup \\ hello equal? [ drop 42 ] when ;",
+            "greet [ hello world ] call",
+        ];
+        assert_eq!(
+            datastack(&run_all(int, "", &Pipeline::level(5), &programs)),
+            "[ world 42 42 ] "
+        );
+    });
+}
+
+const ANNOTATED: &str = "
+    : tiny 1 ;
+    : fast dup * ;
+    : plain 2 ;
+    : user tiny fast plain ;
+    \\ tiny ( no-optimise ) pragmas
+    \\ fast ( level 4 ) pragmas";
+
+#[test]
+fn pragmas_are_read_at_every_level() {
+    with_prelude(|int| {
+        for level in 0..=5 {
+            let int = runner::run(runner::optimise(int.clone(), level), ANNOTATED, level);
+            let pragmas: Vec<_> = int
+                .pragmas
+                .iter()
+                .filter(|(w, _)| ["tiny", "fast", "plain"].contains(&w.as_str()))
+                .map(|(w, p)| (w.as_str(), p.no_optimise, p.level))
+                .collect();
+            assert_eq!(
+                pragmas,
+                vec![("fast", false, Some(4)), ("tiny", true, None)],
+                "level {level}"
+            );
+            assert_eq!(
+                print_stack(&runner::run(int, "3 user", level).datastack, false, false),
+                "[ 2 1 3 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn annotated_words_stay_calls() {
+    with_prelude(|int| {
+        let int = runner::run(int, ANNOTATED, 0);
+        let inlined = preprocess(
+            "user".to_string(),
+            &body(&int, "user"),
+            &int.dictionary,
+            InlineBudget::UNLIMITED,
+            &int.pragmas,
+        );
+
+        assert!(contains_word(&inlined, "tiny"));
+        assert!(contains_word(&inlined, "fast"));
+        assert!(!contains_word(&inlined, "plain"));
+    });
+}
+
+#[test]
+fn words_are_optimised_as_annotated() {
+    with_prelude(|int| {
+        let int = runner::optimise(runner::run(int, ANNOTATED, 0), 2);
+        assert_eq!(
+            body(&int, "tiny"),
+            vec![StackElement::Word("1".to_string())]
+        );
+        assert!(matches!(
+            int.dictionary["fast"].deref(),
+            Funct::Compiled(..)
+        ));
+        assert!(matches!(
+            int.dictionary["plain"].deref(),
+            Funct::SelfDefined(_)
+        ));
+    });
+}
+
+#[test]
+fn inline_ignores_the_budget() {
+    with_prelude(|int| {
+        let int = runner::run(
+            int,
+            &format!("{MID} \\ mid ( inline ) pragmas : once mid ;"),
+            0,
+        );
+        let inlined = preprocess(
+            "once".to_string(),
+            &body(&int, "once"),
+            &int.dictionary,
+            InlineBudget::default(),
+            &int.pragmas,
+        );
+
+        assert_eq!(inlined.len(), 40);
+    });
+}
+
+#[test]
+fn redefinitions_drop_pragmas() {
+    with_prelude(|int| {
+        let int = runner::run(
+            int,
+            ": tiny 1 ; \\ tiny ( no-optimise ) pragmas : tiny 2 ;",
+            0,
+        );
+        assert!(!int.pragmas.contains_key("tiny"));
+    });
+}
+
+#[test]
+fn pragma_names_are_ordinary_words() {
+    with_prelude(|int| {
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    "",
+                    &Pipeline::level(level),
+                    &[": inline 42 ; : foo 1 ; inline"]
+                )),
+                "[ 42 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn invalid_pragmas_are_syntax_errors() {
+    with_prelude(|int| {
+        for pragmas in [
+            "( level 9 )",
+            "( level )",
+            "( inline no-optimise )",
+            "( fast )",
+        ] {
+            let code = format!(": tiny 1 ; \\ tiny {pragmas} pragmas");
+            let int = runner::run(int.clone(), &code, 0);
+            assert_eq!(
+                print_stack(&int.datastack, false, false),
+                "[ _|_ [ invalid pragmas ] syntax-error ] ",
+                "{pragmas}"
+            );
+            assert!(!int.pragmas.contains_key("tiny"), "{pragmas}");
+        }
+    });
+}
+
+#[test]
+fn continuation_words_of_the_prelude_are_not_optimised() {
+    with_prelude(|int| {
+        let int = runner::optimise(int, 2);
+        for word in ["call", "[", ":", "SYMBOL:"] {
+            assert!(int.pragmas[word].no_optimise, "{word}");
+            assert!(
+                !print_ir(&body(&int, word), &int.dictionary).contains('<'),
+                "{word}"
+            );
+        }
+    });
+}
+
+/// The optimiser only parses the brackets of the code between definitions,
+/// so `:` still finds the stack effect of a definition in a program.
+#[test]
+fn definitions_in_programs_keep_their_stack_effect() {
+    with_prelude(|int| {
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(
+                    int.clone(),
+                    "",
+                    &Pipeline::level(level),
+                    &[": sq ( n -- n ) dup * ; 7 sq [ 1 ( 2 ) ] \\ :"]
+                )),
+                "[ : [ 1 [ 2 ] ] 49 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn words_defined_as_other_words_stay_calls() {
+    with_prelude(|int| {
+        let code = ": baz foo ; \\ bar \\ foo get-dict assoc set-dict 1";
+        for level in 0..=5 {
+            assert_eq!(
+                datastack(&run_all(int.clone(), "", &Pipeline::level(level), &[code])),
+                "[ 1 ] ",
+                "level {level}"
+            );
+        }
+    });
+}
@@ -0,0 +1,25 @@
+use consize_interpreter::{
+    runner,
+    stack_element::{print_stack, StackElement},
+};
+
+/// `assoc ( val key map -- map' )` replaces the value of a key the mapping
+/// already has, so `def` can redefine a word.
+#[test]
+fn assoc_replaces_the_value_of_an_existing_key() {
+    let word = |w: &str| StackElement::Word(w.to_string());
+    let map = StackElement::Map(vec![(word("a"), word("1")), (word("b"), word("2"))]);
+
+    let mut int = runner::primitives();
+    int.datastack = vec![word("3"), word("a"), map.clone()];
+    let int = int.assoc();
+    assert_eq!(print_stack(&int.datastack, false, false), "{ a, 3 b, 2 } ");
+
+    let mut int = runner::primitives();
+    int.datastack = vec![word("3"), word("c"), map];
+    let int = int.assoc();
+    assert_eq!(
+        print_stack(&int.datastack, false, false),
+        "{ a, 1 b, 2 c, 3 } "
+    );
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+};
+
+use common::with_prelude;
+use consize_interpreter::{
+    error::{catch, ConsizeError},
+    interpreter::Interpreter,
+    io::{Io, MemoryIo},
+    replay::{RecordingIo, ReplayIo},
+    runner,
+    stack_element::print_stack,
+};
+
+const SESSION: &str = "\\ out.txt read-line spit \\ out.txt \\ in.txt slurp spit-on \
+    \\ out.txt slurp println current-time-millis";
+
+fn log_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("consize-replay-{name}-{}.log", process::id()))
+}
+
+fn run(int: &Interpreter, io: Rc<dyn Io>, code: &str) -> Result<String, ConsizeError> {
+    let int = int.clone().with_io(io);
+    catch(|| print_stack(&runner::run(int, code, 0).datastack, false, false))
+}
+
+#[test]
+fn a_replay_repeats_the_recorded_session() {
+    with_prelude(|int| {
+        let path = log_path("round-trip");
+        let live = Rc::new(
+            MemoryIo::new()
+                .with_input("hello")
+                .with_file("in.txt", "world"),
+        );
+        live.set_time(1234);
+        let recorder = Rc::new(RecordingIo::create(live.clone(), &path).unwrap());
+        let recorded = run(&int, recorder, SESSION).unwrap();
+        assert_eq!(live.file("out.txt").unwrap(), "hello\nworld");
+        assert!(recorded.starts_with("[ 1234 "), "{recorded}");
+
+        // Neither stdin nor the files exist any more, and time went on.
+        let output = Rc::new(MemoryIo::new());
+        let replayer = Rc::new(ReplayIo::open(output.clone(), &path).unwrap());
+        let replayed = run(&int, replayer, SESSION).unwrap();
+        assert_eq!(replayed, recorded);
+        assert_eq!(output.output(), live.output());
+        assert_eq!(output.output(), "hello\nworld\n");
+        assert_eq!(output.file("out.txt"), None);
+
+        fs::remove_file(&path).unwrap();
+    });
+}
+
+#[test]
+fn the_clock_is_replayed() {
+    with_prelude(|int| {
+        let path = log_path("clock");
+        fs::write(&path, "current-time-millis ok 2\n42\n").unwrap();
+        let replayer = Rc::new(ReplayIo::open(Rc::new(MemoryIo::new()), &path).unwrap());
+        assert_eq!(
+            run(&int, replayer, "current-time-millis"),
+            Ok("[ 42 ] ".to_string())
+        );
+        fs::remove_file(&path).unwrap();
+    });
+}
+
+#[test]
+fn a_different_session_diverges() {
+    with_prelude(|int| {
+        let path = log_path("diverge");
+        fs::write(&path, "read-line ok 3\nabc\n").unwrap();
+        let replay = || Rc::new(ReplayIo::open(Rc::new(MemoryIo::new()), &path).unwrap());
+        assert!(matches!(
+            run(&int, replay(), "\\ in.txt slurp"),
+            Err(ConsizeError::Replay(_))
+        ));
+        assert!(matches!(
+            run(&int, replay(), "read-line read-line"),
+            Err(ConsizeError::Replay(_))
+        ));
+        assert_eq!(run(&int, replay(), "read-line"), Ok("[ abc ] ".to_string()));
+        fs::remove_file(&path).unwrap();
+    });
+}
+
+#[test]
+fn a_different_path_or_data_diverges() {
+    with_prelude(|int| {
+        let path = log_path("paths");
+        fs::write(
+            &path,
+            "slurp ok 5 6\nin.txt\nworld\nspit ok 5 7\nout.txt\nworld\n",
+        )
+        .unwrap();
+        let replay = || Rc::new(ReplayIo::open(Rc::new(MemoryIo::new()), &path).unwrap());
+        assert_eq!(
+            run(&int, replay(), "\\ out.txt \\ in.txt slurp spit"),
+            Ok("[ ] ".to_string())
+        );
+        assert!(matches!(
+            run(&int, replay(), "\\ other.txt slurp"),
+            Err(ConsizeError::Replay(reason)) if reason.contains("other.txt")
+        ));
+        assert!(matches!(
+            run(&int, replay(), "\\ other.txt \\ in.txt slurp spit"),
+            Err(ConsizeError::Replay(_))
+        ));
+        assert!(matches!(
+            run(&int, replay(), "\\ out.txt \\ in.txt slurp drop \\ other spit"),
+            Err(ConsizeError::Replay(reason)) if reason.contains("other data")
+        ));
+
+        // Paths with spaces and newlines survive the log.
+        let file = Path::new("my\nfile 1.txt");
+        let recorder = RecordingIo::create(Rc::new(MemoryIo::new()), &path).unwrap();
+        recorder.write_file(file, "data").unwrap();
+        recorder.read_line();
+        drop(recorder);
+        let replayer = replay();
+        assert!(replayer.write_file(file, "data").is_ok());
+        assert_eq!(replayer.read_line(), "");
+        fs::remove_file(&path).unwrap();
+    });
+}
+
+#[test]
+fn damaged_logs_are_rejected() {
+    let path = log_path("damaged");
+    for log in [
+        "read-line ok 5\nabc\n",
+        "read-line ok 3\nabc",
+        "read-line ok\nabc\n",
+        "read-line ok x\nabc\n",
+        "read-line maybe 3\nabc\n",
+        "read-line ok 3 4\nabc\n",
+        "read-line ok 3\nabcd\n",
+        "slurp ok 3\nabc\n",
+    ] {
+        fs::write(&path, log).unwrap();
+        assert!(
+            ReplayIo::open(Rc::new(MemoryIo::new()), &path).is_err(),
+            "{log:?}"
+        );
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+/// Writing to /dev/full fails with a full disk.
+#[cfg(target_os = "linux")]
+#[test]
+fn a_full_disk_stops_the_recording() {
+    with_prelude(|int| {
+        let recorder = Rc::new(RecordingIo::create(Rc::new(MemoryIo::new()), "/dev/full").unwrap());
+        assert!(matches!(
+            run(&int, recorder, "current-time-millis"),
+            Err(ConsizeError::Record(_))
+        ));
+    });
+}
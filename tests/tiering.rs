@@ -0,0 +1,100 @@
+mod common;
+
+use common::{datastack, run_all, with_prelude};
+use consize_interpreter::{interpreter::Interpreter, pass::Pipeline, runner, tiering::Tiering};
+
+/// `int` with words promoted to `level` after three calls.
+fn tiered(int: Interpreter, level: u8) -> Interpreter {
+    int.with_tiering(Tiering::new(level, 3))
+}
+
+fn promoted(int: &Interpreter) -> Vec<String> {
+    int.tiering
+        .as_ref()
+        .unwrap()
+        .promoted()
+        .into_iter()
+        .collect()
+}
+
+#[test]
+fn hot_words_are_promoted() {
+    with_prelude(|int| {
+        let programs = [
+            ": inc 1 + ; : here [ continue ] call/cc ;",
+            "0 inc inc inc inc here here here here",
+        ];
+        for level in [2, 4] {
+            let int = run_all(
+                tiered(int.clone(), level),
+                "",
+                &Pipeline::level(0),
+                &programs,
+            );
+            assert_eq!(datastack(&int), "[ 4 ] ");
+            assert!(promoted(&int).contains(&"inc".to_string()));
+            assert!(!promoted(&int).contains(&"here".to_string()));
+        }
+    });
+}
+
+#[test]
+fn cold_words_stay_at_level_0() {
+    with_prelude(|int| {
+        let int = run_all(
+            tiered(int, 4),
+            "",
+            &Pipeline::level(0),
+            &[": inc 1 + ;", "0 inc inc"],
+        );
+        assert!(!promoted(&int).contains(&"inc".to_string()));
+    });
+}
+
+#[test]
+fn redefinitions_demote_their_users() {
+    with_prelude(|int| {
+        let programs = [
+            ": inc 1 + ; : twice inc inc ;",
+            "0 twice twice twice twice",
+            ": inc 10 + ;",
+        ];
+        for level in [2, 4] {
+            let int = run_all(
+                tiered(int.clone(), level),
+                "",
+                &Pipeline::level(0),
+                &programs,
+            );
+            assert!(!promoted(&int).contains(&"twice".to_string()));
+            let int = runner::run(int, "0 twice", 0);
+            assert_eq!(datastack(&int), "[ 20 ] ");
+        }
+    });
+}
+
+#[test]
+fn hot_quotations_are_promoted() {
+    with_prelude(|int| {
+        let count = ": count ( n -- n ) dup 3000 < [ 1 + count ] when ;";
+        // The steps the loop takes, most of them in the quotation passed to
+        // `call`.
+        let steps = |int: Interpreter| {
+            let int = runner::run(int, count, 0);
+            let before = int.steps;
+            let int = runner::run(int, "0 count", 0);
+            assert_eq!(datastack(&int), "[ 3000 ] ");
+            (int.steps - before, int)
+        };
+        let (plain, _) = steps(int.clone());
+        for level in [2, 4] {
+            let (promoted_steps, int) = steps(tiered(int.clone(), level));
+            assert!(promoted(&int).contains(&"count".to_string()));
+            assert!(int.tiering.as_ref().unwrap().promoted_quotations() > 0);
+            assert!(
+                promoted_steps * 2 < plain,
+                "level {level}: {promoted_steps} steps, {plain} at level 0"
+            );
+        }
+    });
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use std::{env, fs, process::Command};
+
+use common::with_prelude;
+use consize_interpreter::{runner, transpile::transpile};
+
+#[test]
+fn programs_call_resolved_primitives() {
+    with_prelude(|int| {
+        let int = runner::optimise(int, 2);
+        let krate = transpile(&int, "1 2 + \\ x", "sum", None).unwrap();
+
+        assert!(krate.manifest.contains("name = \"sum\""));
+        assert!(krate
+            .main
+            .contains("vec![escape(w(\"x\")), r(p, \"+\"), w(\"2\"), w(\"1\")]"));
+        assert!(krate
+            .main
+            .contains("pipeline: Pipeline::parse(\"inline,resolve\")"));
+    });
+}
+
+#[test]
+fn definitions_are_kept_as_written() {
+    with_prelude(|int| {
+        let int = runner::optimise(int, 2);
+        let krate = transpile(&int, ": sq dup * ; 3 sq", "sq", None).unwrap();
+
+        assert!(krate.main.contains(
+            "vec![w(\"sq\"), w(\"3\"), w(\";\"), w(\"*\"), w(\"dup\"), w(\"sq\"), w(\":\")]"
+        ));
+    });
+}
+
+#[test]
+fn crates_depend_on_this_checkout_unless_given_a_runtime() {
+    with_prelude(|int| {
+        let int = runner::optimise(int, 2);
+        let this = transpile(&int, "1", "one", None).unwrap();
+        let path = format!(
+            "consize-interpreter = {{ path = {:?} }}\n",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        assert!(this.manifest.contains(&path));
+
+        let local = transpile(&int, "1", "one", Some("/opt/consize".as_ref())).unwrap();
+        assert!(local
+            .manifest
+            .contains("consize-interpreter = { path = \"/opt/consize\" }\n"));
+    });
+}
+
+#[test]
+fn compiling_pipelines_are_rejected() {
+    with_prelude(|int| {
+        for level in 3..=5 {
+            assert!(transpile(&runner::optimise(int.clone(), level), "1", "x", None).is_err());
+        }
+    });
+}
+
+#[test]
+fn crates_are_written_to_disk() {
+    with_prelude(|int| {
+        let dir = env::temp_dir().join(format!("consize-transpile-{}", std::process::id()));
+        let krate = transpile(&int, "1", "one", None).unwrap();
+        krate.write(&dir).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("Cargo.toml")).unwrap(),
+            krate.manifest
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("src/main.rs")).unwrap(),
+            krate.main
+        );
+        fs::remove_dir_all(dir).unwrap();
+    });
+}
+
+#[test]
+fn package_names_are_sanitised() {
+    with_prelude(|int| {
+        for (name, package) in [
+            ("my prog", "my-prog"),
+            ("2fast", "consize-2fast"),
+            ("test", "consize-test"),
+            ("süß", "s--"),
+        ] {
+            let krate = transpile(&int, "1", name, None).unwrap();
+            assert!(
+                krate.manifest.contains(&format!("name = \"{package}\"")),
+                "{name}: {}",
+                krate.manifest
+            );
+        }
+    });
+}
+
+/// Builds the crate with the same toolchain, which takes a while.
+#[test]
+#[ignore]
+fn generated_crates_build_and_run() {
+    with_prelude(|int| {
+        let int = runner::optimise(int, 2);
+        let dir = env::temp_dir().join(format!("consize transpile {}", std::process::id()));
+        transpile(&int, ": sq dup * ; 3 sq args", "my prog", None)
+            .unwrap()
+            .write(&dir)
+            .unwrap();
+
+        let output = Command::new(env!("CARGO"))
+            .args(["run", "--quiet", "--manifest-path"])
+            .arg(dir.join("Cargo.toml"))
+            .args(["--", "a", "b"])
+            .env("CARGO_TARGET_DIR", env!("CARGO_TARGET_TMPDIR"))
+            .output()
+            .unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "[ [ a b ] 9 ] \n");
+    });
+}
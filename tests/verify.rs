@@ -0,0 +1,375 @@
+mod common;
+
+use std::rc::Rc;
+
+use common::with_prelude;
+use consize_interpreter::{
+    environment::Environment,
+    interpreter::Interpreter,
+    io::{Io, MemoryIo},
+    pass::Pipeline,
+    runner,
+    verify::{verify, verify_against, verify_with, Divergence},
+};
+use proptest::{
+    collection::vec,
+    prelude::*,
+    test_runner::{Config, TestRunner},
+};
+
+/// Words working on numbers, with the number of elements they need and the
+/// change in stack depth they cause.
+const WORDS: [(&str, usize, isize); 16] = [
+    ("dup", 1, 1),
+    ("drop", 1, -1),
+    ("swap", 2, 0),
+    ("over", 2, 1),
+    ("rot", 3, 0),
+    ("-rot", 3, 0),
+    ("nip", 2, -1),
+    ("2dup", 2, 2),
+    ("2drop", 2, -2),
+    ("+", 2, -1),
+    ("-", 2, -1),
+    ("*", 2, -1),
+    ("dupd", 2, 1),
+    ("swapd", 3, 0),
+    ("pick", 3, 1),
+    ("3dup", 3, 3),
+];
+
+/// The words `def0` to `def3` with the inputs and outputs they have in
+/// [`PREDEFINED`] and keep when a program redefines them.
+const DEFINED: [(usize, usize); 4] = [(0, 1), (1, 1), (2, 1), (1, 2)];
+
+/// The definitions programs start with. `def3` uses `def1` and `def1` uses
+/// `def0`, so redefining a word changes the words using it as well.
+const PREDEFINED: &str = ": def0 ( -- x0 ) 0 ;
+    : def1 ( x0 -- x0 ) def0 + ;
+    : def2 ( x0 x1 -- x0 ) + ;
+    : def3 ( x0 -- x0 x1 ) def1 dup ;";
+
+/// A program fragment. Rendering turns it into well-formed Consize code by
+/// pushing numbers whenever a fragment needs more than the stack holds.
+#[derive(Clone, Debug)]
+enum Op {
+    Number(u8),
+    Escaped(u8),
+    Word(usize),
+    Print,
+    /// Pushes a number through the reified datastack of `call/cc`.
+    CallCc(u8),
+    /// Calls one of the words `def0` to `def3`.
+    Defined(usize),
+    Call(Vec<Op>),
+    Dip(Vec<Op>),
+    Keep(Vec<Op>),
+    If(u8, Vec<Op>, Vec<Op>),
+    Loop(u8, Vec<Op>),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let leaf = prop_oneof![
+        (0..10u8).prop_map(Op::Number),
+        (0..10u8).prop_map(Op::Escaped),
+        (0..WORDS.len()).prop_map(Op::Word),
+        Just(Op::Print),
+        (0..10u8).prop_map(Op::CallCc),
+        (0..DEFINED.len()).prop_map(Op::Defined),
+    ];
+    leaf.prop_recursive(3, 32, 6, |inner| {
+        let body = move || vec(inner.clone(), 0..6);
+        prop_oneof![
+            body().prop_map(Op::Call),
+            body().prop_map(Op::Dip),
+            body().prop_map(Op::Keep),
+            (0..10u8, body(), body()).prop_map(|(n, a, b)| Op::If(n, a, b)),
+            (1..4u8, body()).prop_map(|(n, b)| Op::Loop(n, b)),
+        ]
+    })
+}
+
+struct Source {
+    words: Vec<String>,
+    depth: usize,
+    /// How many of `def0` to `def3` may be called. The definition of a word
+    /// only calls the ones before it, so redefinitions never recurse.
+    callable: usize,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self {
+            words: Vec::new(),
+            depth: 0,
+            callable: DEFINED.len(),
+        }
+    }
+}
+
+impl Source {
+    fn at(&self, depth: usize) -> Self {
+        Self {
+            words: Vec::new(),
+            depth,
+            callable: self.callable,
+        }
+    }
+
+    fn emit(&mut self, code: &str, effect: isize) {
+        self.words.push(code.to_string());
+        self.depth = self.depth.checked_add_signed(effect).unwrap();
+    }
+
+    fn need(&mut self, depth: usize) {
+        while self.depth < depth {
+            self.emit("1", 1);
+        }
+    }
+
+    fn settle(&mut self, depth: usize) {
+        while self.depth > depth {
+            self.emit("drop", -1);
+        }
+        self.need(depth);
+    }
+
+    /// Renders `ops` as a new definition of `def{i}`, with a stack effect
+    /// naming its inputs and outputs.
+    fn define(&mut self, i: usize, ops: &[Op]) {
+        let (inputs, outputs) = DEFINED[i];
+        let mut body = self.at(inputs);
+        body.callable = i;
+        body.render(ops);
+        body.settle(outputs);
+        let name = |i| format!("x{i}");
+        let effect = format!(
+            "( {} -- {} )",
+            (0..inputs).map(name).collect::<Vec<_>>().join(" "),
+            (0..outputs).map(name).collect::<Vec<_>>().join(" ")
+        );
+        self.emit(&format!(": def{i} {effect} {} ;", body.words.join(" ")), 0);
+    }
+
+    /// Renders `ops` as a quotation starting at `depth`.
+    fn quote(&self, ops: &[Op], depth: usize) -> Self {
+        let mut body = self.at(depth);
+        body.render(ops);
+        body.words.insert(0, "[".to_string());
+        body.words.push("]".to_string());
+        body
+    }
+
+    fn render(&mut self, ops: &[Op]) {
+        for op in ops {
+            match op {
+                Op::Number(n) => self.emit(&n.to_string(), 1),
+                Op::Escaped(n) => self.emit(&format!("\\ {n}"), 1),
+                Op::Word(i) => {
+                    let (word, needs, effect) = WORDS[*i];
+                    self.need(needs);
+                    self.emit(word, effect);
+                }
+                Op::Print => {
+                    self.need(1);
+                    self.emit("dup println", 0);
+                }
+                Op::CallCc(n) => self.emit(&format!("[ [ {n} push ] dip continue ] call/cc"), 1),
+                Op::Defined(_) if self.callable == 0 => self.emit("1", 1),
+                Op::Defined(i) => {
+                    let i = i % self.callable;
+                    let (inputs, outputs) = DEFINED[i];
+                    self.need(inputs);
+                    self.emit(&format!("def{i}"), outputs as isize - inputs as isize);
+                }
+                Op::Call(ops) => {
+                    let body = self.quote(ops, self.depth);
+                    self.emit(&body.words.join(" "), 0);
+                    self.emit("call", body.depth as isize - self.depth as isize);
+                }
+                Op::Dip(ops) => {
+                    self.need(1);
+                    let body = self.quote(ops, self.depth - 1);
+                    self.emit(&body.words.join(" "), 0);
+                    self.emit("dip", body.depth as isize + 1 - self.depth as isize);
+                }
+                Op::Keep(ops) => {
+                    self.need(1);
+                    let body = self.quote(ops, self.depth);
+                    self.emit(&body.words.join(" "), 0);
+                    self.emit("keep", body.depth as isize + 1 - self.depth as isize);
+                }
+                Op::If(n, then, otherwise) => {
+                    self.need(1);
+                    let then = self.quote(then, self.depth);
+                    let mut otherwise = self.quote(otherwise, self.depth);
+                    otherwise.words.pop();
+                    otherwise.settle(then.depth);
+                    otherwise.words.push("]".to_string());
+                    self.emit(&format!("dup {n} <"), 0);
+                    self.emit(&then.words.join(" "), 0);
+                    self.emit(&otherwise.words.join(" "), 0);
+                    self.emit("if", then.depth as isize - self.depth as isize);
+                }
+                Op::Loop(n, ops) => {
+                    let mut body = self.quote(ops, self.depth);
+                    body.words.pop();
+                    body.settle(self.depth);
+                    body.words.push("]".to_string());
+                    let body = body.words.join(" ");
+                    self.emit(&format!("{n} [ {body} dip 1 - dup 0 > ] loop drop"), 0);
+                }
+            }
+        }
+    }
+}
+
+fn program() -> impl Strategy<Value = String> {
+    let definitions = vec((0..DEFINED.len(), vec(op(), 0..6)), 0..3);
+    (definitions, vec(op(), 1..12)).prop_map(|(definitions, ops)| {
+        let mut source = Source::default();
+        for (i, body) in &definitions {
+            source.define(*i, body);
+        }
+        source.render(&ops);
+        source.words.join(" ")
+    })
+}
+
+#[test]
+fn random_programs_agree_at_every_level() {
+    with_prelude(|int| {
+        let int = runner::run(int, PREDEFINED, 0);
+        let optimised: Vec<Interpreter> = (1..=5)
+            .map(|level| runner::optimise(int.clone(), level))
+            .collect();
+        let mut runner = TestRunner::new(Config {
+            cases: 64,
+            failure_persistence: None,
+            ..Config::default()
+        });
+
+        runner
+            .run(&program(), |code| {
+                for (level, opt) in (1..=5).zip(&optimised) {
+                    if let Err(divergence) = verify_against(&int, opt, &code, level) {
+                        return Err(TestCaseError::fail(format!(
+                            "level {level} diverges on `{code}`: {divergence}"
+                        )));
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+    });
+}
+
+#[test]
+fn random_programs_agree_after_folding() {
+    with_prelude(|int| {
+        let int = runner::run(int, PREDEFINED, 0);
+        let pipelines: Vec<Pipeline> = ["fold", "inline,fold,resolve", "compile,fold,compose"]
+            .into_iter()
+            .map(|passes| Pipeline::parse(passes).unwrap())
+            .collect();
+        let mut runner = TestRunner::new(Config {
+            cases: 32,
+            failure_persistence: None,
+            ..Config::default()
+        });
+
+        runner
+            .run(&program(), |code| {
+                for pipeline in &pipelines {
+                    if let Err(divergence) = verify_with(int.clone(), &code, pipeline) {
+                        return Err(TestCaseError::fail(format!(
+                            "passes {pipeline} diverge on `{code}`: {divergence}"
+                        )));
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+    });
+}
+
+#[test]
+fn verify_reports_output_and_datastack() {
+    with_prelude(|int| {
+        let outcome = verify(int, "1 2 + dup println \\ x", 4).unwrap();
+        assert_eq!(outcome.output, "3\n");
+        assert_eq!(
+            outcome.result.unwrap().last().unwrap().to_string(),
+            "[ x 3 ]"
+        );
+    });
+}
+
+#[test]
+fn verify_reports_first_divergence() {
+    with_prelude(|int| {
+        // The continuation holds the rest of `foo`, in which `two` is
+        // inlined.
+        let divergence = verify(int, ": two 2 ; : foo [ ] call/cc two ; 1 foo", 1).unwrap_err();
+        assert!(matches!(divergence, Divergence::Datastack { index: 0, .. }));
+    });
+}
+
+#[test]
+fn stack_and_mapping_literals_stay_data() {
+    with_prelude(|int| {
+        // `f` is a word pushing itself, which must not be inlined in data.
+        let code = "( f ) [ ] each ( f f ) f [ or ] reduce \\ f { \\ f ( f ) } nil get";
+        for level in 1..=5 {
+            let outcome = verify(int.clone(), code, level).unwrap();
+            assert_eq!(
+                outcome.result.unwrap().last().unwrap().to_string(),
+                "[ [ f ] f f ]",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn the_virtual_clock_agrees_at_every_level() {
+    with_prelude(|int| {
+        let int = int.with_environment(Environment::Deterministic { seed: 0 });
+        let code = "current-time-millis 1 2 + current-time-millis [ current-time-millis ] call";
+        for level in 1..=5 {
+            let outcome = verify(int.clone(), code, level).unwrap();
+            assert_eq!(
+                outcome.result.unwrap().last().unwrap().to_string(),
+                "[ 2 1 3 0 ]",
+                "level {level}"
+            );
+        }
+    });
+}
+
+#[test]
+fn side_effects_happen_once() {
+    with_prelude(|int| {
+        let code = "\\ log.txt read-line spit-on \\ log.txt slurp current-time-millis drop";
+        for level in 1..=5 {
+            let io = Rc::new(
+                MemoryIo::new()
+                    .with_input("first")
+                    .with_input("second")
+                    .with_file("log.txt", "start "),
+            );
+            let outcome = verify(int.clone().with_io(io.clone()), code, level).unwrap();
+            assert_eq!(
+                outcome.result.unwrap().last().unwrap().to_string(),
+                "[ start first\n ]",
+                "level {level}"
+            );
+            assert_eq!(
+                io.file("log.txt").unwrap(),
+                "start first\n",
+                "level {level}"
+            );
+            assert_eq!(io.read_line(), "second\n", "level {level}");
+        }
+    });
+}